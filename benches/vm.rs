@@ -0,0 +1,53 @@
+use std::{
+    ffi::OsStr,
+    time::{Duration, Instant},
+};
+
+use flock::{rand::Rand, spawn_host, Eal, Program};
+
+const RUNS: usize = 10;
+
+struct BenchEal;
+
+#[async_trait::async_trait]
+impl Eal for BenchEal {
+    fn rand(&self) -> Rand {
+        Rand::new(0)
+    }
+}
+
+/// Times each program in `benches/`, reporting the median and fastest of several runs.
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let mut paths = std::fs::read_dir("benches")?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        let extension = path.extension();
+        extension == Some(OsStr::new("flasm")) || extension == Some(OsStr::new("fl"))
+    });
+    paths.sort();
+
+    for path in paths {
+        let program = Program::load_file(&path)?;
+
+        let mut times = Vec::with_capacity(RUNS);
+        for _ in 0..RUNS {
+            let host = spawn_host(BenchEal).await?;
+            let start = Instant::now();
+            let code = host.execute(program.clone()).await?;
+            times.push(start.elapsed());
+            eyre::ensure!(code == 0, "{} exited with code: {code}", path.display());
+        }
+        times.sort();
+
+        println!(
+            "bench {} ... median {:?}, fastest {:?}",
+            path.display(),
+            times[RUNS / 2],
+            times.first().copied().unwrap_or(Duration::ZERO),
+        );
+    }
+
+    Ok(())
+}
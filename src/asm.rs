@@ -0,0 +1,933 @@
+mod diagnostic;
+mod disasm;
+mod expr;
+mod fmt;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context as _, OptionExt};
+
+pub(crate) use diagnostic::{ParseErrors, Source, Span};
+pub use fmt::format_flasm;
+
+use crate::{to_global, OpCode, Program, ValSp, Word, DATA_BASE, WORD_SIZE};
+
+impl Program {
+    /// Parses a single flasm module. `.include`s are resolved relative to the current directory.
+    pub fn parse(s: &str) -> eyre::Result<Program> {
+        let mut linker = Linker::default();
+        let main = linker.assemble("<input>", s, Path::new(""));
+        linker.link(main)
+    }
+
+    /// Parses the flasm module at `path` and links in any modules it `.include`s.
+    pub fn parse_file(path: &Path) -> eyre::Result<Program> {
+        let mut linker = Linker::default();
+        let main = linker.load(path)?;
+        linker.link(main)
+    }
+}
+
+/// Loads flasm modules and lays them out into a single program.
+#[derive(Default)]
+struct Linker {
+    modules: Vec<Module>,
+    by_path: HashMap<PathBuf, usize>,
+    loading: Vec<PathBuf>,
+    sources: Vec<Source>,
+    /// Errors are collected so they can all be reported once every module is assembled.
+    errors: Vec<(Span, eyre::Report)>,
+}
+
+struct Module {
+    source: usize,
+    symbols: Symbols,
+    lines: Vec<Line>,
+    data: Vec<(Span, DataItem)>,
+    global_data: Vec<(Span, DataItem)>,
+}
+
+/// A line of source, without its comment or surrounding whitespace.
+#[derive(Debug, Clone)]
+struct Line {
+    text: String,
+    span: Span,
+}
+
+impl Line {
+    /// The code on line `index` of `source`, or `None` if it's blank or only a comment.
+    fn parse(source: usize, index: usize, line: &str) -> Option<Line> {
+        let code = strip_comment(line);
+        let text = code.trim();
+        (!text.is_empty()).then(|| Line {
+            text: text.to_string(),
+            span: Span {
+                source,
+                line: index + 1,
+                column: code.len() - code.trim_start().len() + 1,
+                len: text.len(),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+    GlobalData,
+}
+
+enum DataItem {
+    Label(String),
+    /// Values are resolved once all labels are known.
+    Words(Vec<String>),
+    Bytes(Vec<u8>),
+}
+
+impl DataItem {
+    fn size(&self) -> Word {
+        match self {
+            DataItem::Label(_) => 0,
+            DataItem::Words(words) => words.len() as Word * WORD_SIZE,
+            DataItem::Bytes(bytes) => (bytes.len() as Word).next_multiple_of(WORD_SIZE),
+        }
+    }
+}
+
+impl Linker {
+    fn load(&mut self, path: &Path) -> eyre::Result<usize> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Resolving {}", path.display()))?;
+        if let Some(&index) = self.by_path.get(&canonical) {
+            return Ok(index);
+        }
+        eyre::ensure!(
+            !self.loading.contains(&canonical),
+            "Include cycle through {}",
+            path.display()
+        );
+
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        self.loading.push(canonical.clone());
+        let index = self.assemble(&path.display().to_string(), &contents, dir);
+        self.loading.pop();
+
+        self.by_path.insert(canonical, index);
+        Ok(index)
+    }
+
+    /// Expands the module `s`, which is called `name` in errors.
+    fn assemble(&mut self, name: &str, s: &str, dir: &Path) -> usize {
+        self.sources.push(Source {
+            name: name.to_string(),
+            text: s.to_string(),
+        });
+        let source = self.sources.len() - 1;
+
+        let relevant_lines = s
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| Line::parse(source, i, line))
+            .collect::<Vec<_>>();
+
+        let mut expander = Expander {
+            dir: dir.to_owned(),
+            symbols: Symbols::default(),
+            macros: Default::default(),
+            expansion_count: 0,
+            gave_up: false,
+            section: Section::Text,
+            lines: Vec::new(),
+            data: Vec::new(),
+            global_data: Vec::new(),
+        };
+        expander.expand(relevant_lines, 0, self);
+
+        self.modules.push(Module {
+            source,
+            symbols: expander.symbols,
+            lines: expander.lines,
+            data: expander.data,
+            global_data: expander.global_data,
+        });
+        self.modules.len() - 1
+    }
+
+    /// Lays out `main` first, followed by every module it includes.
+    fn link(mut self, main: usize) -> eyre::Result<Program> {
+        let order = std::iter::once(main)
+            .chain((0..self.modules.len()).filter(|&i| i != main))
+            .collect::<Vec<_>>();
+
+        if order.len() > 1 {
+            // Running off the end of the main module shouldn't fall through into a library.
+            let source = self.modules[main].source;
+            let end = Span {
+                source,
+                line: self.sources[source].text.lines().count() + 1,
+                column: 1,
+                len: 0,
+            };
+            self.modules[main].lines.push(Line {
+                text: "EXIT 0".to_string(),
+                span: end,
+            });
+        }
+
+        let mut ops_seen = 0;
+        for &i in &order {
+            let module = &mut self.modules[i];
+            for line in &module.lines {
+                if let Some(label) = line.text.strip_prefix(":") {
+                    if let Err(e) = module.symbols.define_label(label, ops_seen) {
+                        self.errors.push((line.span, e));
+                    }
+                } else {
+                    ops_seen += 1;
+                }
+            }
+        }
+
+        let data = self.layout_data(&order, DATA_BASE, |m| &m.data);
+        let global_data = self.layout_data(&order, to_global(DATA_BASE), |m| &m.global_data);
+        for module in &mut self.modules {
+            module.symbols.laid_out = true;
+        }
+
+        for &i in &order {
+            let symbols = &self.modules[i].symbols;
+            for (export, span) in &symbols.exports {
+                if !symbols.defines(export) {
+                    let e = eyre::eyre!("Exported symbol not defined: {export}");
+                    self.errors.push((*span, e));
+                }
+            }
+        }
+
+        let mut ops = Vec::new();
+        let mut spans = Vec::new();
+        for &i in &order {
+            let module = &self.modules[i];
+            let scope = Scope {
+                symbols: &module.symbols,
+                modules: &self.modules,
+            };
+
+            for line in &module.lines {
+                if line.text.starts_with(":") {
+                    continue;
+                }
+
+                let (command, args) = split_command(&line.text);
+                if !OpCode::NAMES.contains(&command) {
+                    let span = line.span.narrow(&self.sources, command);
+                    self.errors
+                        .push((span, eyre::eyre!("Unknown command: {command}")));
+                    continue;
+                }
+
+                let args_span = line
+                    .span
+                    .narrow(&self.sources, line.text[command.len()..].trim_start());
+                let mut values = Vec::new();
+                for arg in &args {
+                    match ValSp::parse(arg, &scope) {
+                        Ok(v) => values.push(v),
+                        Err(e) => self.errors.push((args_span.narrow(&self.sources, arg), e)),
+                    }
+                }
+                if values.len() < args.len() {
+                    continue;
+                }
+
+                match OpCode::from_args(command, values) {
+                    Ok(op) => {
+                        ops.push(op);
+                        spans.push(line.span);
+                    }
+                    Err(e) => self.errors.push((line.span, e)),
+                }
+            }
+        }
+
+        let data = self.fill_data(&order, &data, |m| &m.data);
+        let global_data = self.fill_data(&order, &global_data, |m| &m.global_data);
+
+        if !self.errors.is_empty() {
+            return Err(ParseErrors::new("assemble", &self.sources, self.errors).into());
+        }
+
+        Ok(Program {
+            ops,
+            spans,
+            sources: self.sources,
+            data,
+            global_data,
+        })
+    }
+
+    /// Defines labels for one data section of each module, returning where each module's data
+    /// starts.
+    fn layout_data(
+        &mut self,
+        order: &[usize],
+        base: Word,
+        section: impl Fn(&Module) -> &Vec<(Span, DataItem)>,
+    ) -> HashMap<usize, Word> {
+        // Each data section gets as much address space as the region below it.
+        let limit = base + DATA_BASE;
+
+        let mut starts = HashMap::new();
+        let mut addr = base;
+        for &i in order {
+            starts.insert(i, addr);
+
+            let module = &mut self.modules[i];
+            let mut labels = Vec::new();
+            for (span, item) in section(module) {
+                if let DataItem::Label(label) = item {
+                    labels.push((*span, label.clone(), addr));
+                }
+                match addr.checked_add(item.size()).filter(|&end| end <= limit) {
+                    Some(end) => addr = end,
+                    None => {
+                        let e = eyre::eyre!("Data section overflows its address range");
+                        self.errors.push((*span, e));
+                        return starts;
+                    }
+                }
+            }
+            for (i, (span, label, start)) in labels.iter().enumerate() {
+                let end = labels[i..]
+                    .iter()
+                    .map(|(_, _, a)| *a)
+                    .find(|a| a > start)
+                    .unwrap_or(addr);
+                if let Err(e) = module.symbols.define_label(label, *start) {
+                    self.errors.push((*span, e));
+                }
+                module.symbols.sizes.insert(label.clone(), end - start);
+            }
+        }
+        starts
+    }
+
+    fn fill_data(
+        &mut self,
+        order: &[usize],
+        starts: &HashMap<usize, Word>,
+        section: impl Fn(&Module) -> &Vec<(Span, DataItem)>,
+    ) -> BTreeMap<Word, Word> {
+        let mut memory = BTreeMap::new();
+        for &i in order {
+            let module = &self.modules[i];
+            let scope = Scope {
+                symbols: &module.symbols,
+                modules: &self.modules,
+            };
+
+            let Some(&start) = starts.get(&i) else {
+                continue;
+            };
+            let mut addr = start;
+            for (span, item) in section(module) {
+                let words = match item {
+                    DataItem::Label(_) => Vec::new(),
+                    DataItem::Words(words) => words
+                        .iter()
+                        .map(|w| {
+                            scope.parse_value(w).unwrap_or_else(|e| {
+                                self.errors.push((span.narrow(&self.sources, w), e));
+                                0
+                            })
+                        })
+                        .collect(),
+                    DataItem::Bytes(bytes) => bytes
+                        .chunks(WORD_SIZE as usize)
+                        .map(|chunk| {
+                            let mut word = [0; WORD_SIZE as usize];
+                            word[..chunk.len()].copy_from_slice(chunk);
+                            Word::from_le_bytes(word)
+                        })
+                        .collect(),
+                };
+
+                for word in words {
+                    if word != 0 {
+                        memory.insert(addr, word);
+                    }
+                    addr += WORD_SIZE;
+                }
+            }
+        }
+        memory
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parses a double-quoted string with `\n`, `\t`, `\0`, `\\` and `\"` escapes.
+fn parse_string(s: &str) -> eyre::Result<Vec<u8>> {
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_eyre(format!("Expected quoted string: {s}"))?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                other => eyre::bail!("Unknown escape in string: \\{}", other.unwrap_or(' ')),
+            },
+            '"' => eyre::bail!("Unescaped '\"' in string: {s}"),
+            c => c,
+        };
+        bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Splits a line into its command and comma separated arguments.
+fn split_command(line: &str) -> (&str, Vec<&str>) {
+    match line.split_once(char::is_whitespace) {
+        None => (line, Vec::new()),
+        Some((command, args)) => (command, split_args(args)),
+    }
+}
+
+/// Splits on commas that aren't inside strings or brackets, trimming each argument.
+fn split_args(args: &str) -> Vec<&str> {
+    if args.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut result = Vec::new();
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                result.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(args[start..].trim());
+    result
+}
+
+const MAX_MACRO_DEPTH: usize = 64;
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+/// Processes directives and macros, leaving only labels and ops.
+struct Expander {
+    dir: PathBuf,
+    symbols: Symbols,
+    macros: HashMap<String, Macro>,
+    expansion_count: usize,
+    /// Set when a macro limit is hit, stopping all expansion rather than hitting it again in
+    /// every other branch.
+    gave_up: bool,
+    section: Section,
+    lines: Vec<Line>,
+    data: Vec<(Span, DataItem)>,
+    global_data: Vec<(Span, DataItem)>,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+impl Expander {
+    /// Expands `lines`, recording errors in `linker` so the remaining lines are still checked.
+    fn expand(&mut self, lines: Vec<Line>, depth: usize, linker: &mut Linker) {
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            if self.gave_up {
+                return;
+            }
+            if let Err(e) = self.expand_line(&line, &mut lines, depth, linker) {
+                linker.errors.push((line.span, e));
+            }
+        }
+    }
+
+    fn expand_line(
+        &mut self,
+        line: &Line,
+        rest: &mut impl Iterator<Item = Line>,
+        depth: usize,
+        linker: &mut Linker,
+    ) -> eyre::Result<()> {
+        if let Some(directive) = line.text.strip_prefix(".") {
+            if let Some(signature) = directive.strip_prefix("macro ") {
+                let mut body = Vec::new();
+                loop {
+                    let line = rest
+                        .next()
+                        .ok_or_eyre(format!("Missing .endmacro for .macro {signature}"))?;
+                    if line.text == ".endmacro" {
+                        break;
+                    }
+                    body.push(line);
+                }
+                return self.define_macro(signature, body);
+            }
+
+            return self.expand_directive(directive, line.span, linker);
+        }
+
+        let (command, args) = split_command(&line.text);
+        if self.macros.contains_key(command) {
+            if depth >= MAX_MACRO_DEPTH {
+                self.gave_up = true;
+                eyre::bail!("Macro expansion too deep expanding {command}");
+            }
+            if self.expansion_count >= MAX_MACRO_EXPANSIONS {
+                self.gave_up = true;
+                eyre::bail!("Too many macro expansions expanding {command}");
+            }
+            let expanded = self
+                .invoke_macro(command, &args)
+                .context(format!("Expanding macro {command}"))?;
+            self.expand(expanded, depth + 1, linker);
+            return Ok(());
+        }
+
+        match (self.section, line.text.strip_prefix(":")) {
+            (Section::Text, _) => self.lines.push(line.clone()),
+            (_, Some(label)) => {
+                let label = DataItem::Label(label.to_string());
+                self.data_section()?.push((line.span, label));
+            }
+            (_, None) => eyre::bail!("Instruction in data section: {}", line.text),
+        }
+        Ok(())
+    }
+
+    fn data_section(&mut self) -> eyre::Result<&mut Vec<(Span, DataItem)>> {
+        match self.section {
+            Section::Text => eyre::bail!("Data outside of .data or .gdata section"),
+            Section::Data => Ok(&mut self.data),
+            Section::GlobalData => Ok(&mut self.global_data),
+        }
+    }
+
+    fn expand_directive(
+        &mut self,
+        directive: &str,
+        span: Span,
+        linker: &mut Linker,
+    ) -> eyre::Result<()> {
+        let (name, args) = directive
+            .split_once(char::is_whitespace)
+            .map(|(name, args)| (name, args.trim()))
+            .unwrap_or((directive, ""));
+        let scope = Scope {
+            symbols: &self.symbols,
+            modules: &linker.modules,
+        };
+
+        match name {
+            // .equ NAME, VALUE
+            "equ" => {
+                let [constant, value] = split_args(args)[..] else {
+                    eyre::bail!("Expected .equ NAME, VALUE");
+                };
+                let value = scope.parse_value(value)?;
+                self.symbols.define_constant(constant, value)?;
+            }
+            // .define NAME VALUE
+            "define" => {
+                let (constant, value) = args
+                    .split_once(char::is_whitespace)
+                    .ok_or_eyre("Expected .define NAME VALUE")?;
+                let value = scope.parse_value(value)?;
+                self.symbols.define_constant(constant, value)?;
+            }
+
+            // .table :NAME, :a, :b, ...
+            // Places the addresses of each label under :NAME for use with JUMP_TABLE, in .gdata
+            // after that directive and otherwise in .data, so the table is loaded before the
+            // program starts even when the directive is among the code.
+            "table" => {
+                let args = split_args(args);
+                let (name, entries) = args
+                    .split_first()
+                    .ok_or_eyre("Too few arguments to .table")?;
+                let label = name
+                    .strip_prefix(":")
+                    .ok_or_eyre(format!("Expected a label naming the table: {name}"))?;
+
+                let entries = entries.iter().map(|e| e.to_string()).collect();
+                let section = match self.section {
+                    Section::GlobalData => &mut self.global_data,
+                    Section::Text | Section::Data => &mut self.data,
+                };
+                section.push((span, DataItem::Label(label.to_string())));
+                section.push((span, DataItem::Words(entries)));
+            }
+
+            // Following labels and data are placed in thread-local memory (.data), global memory
+            // (.gdata) or the program (.text).
+            "text" => self.section = Section::Text,
+            "data" => self.section = Section::Data,
+            "gdata" => self.section = Section::GlobalData,
+
+            // .word VALUE, ...
+            "word" => {
+                let words = split_args(args)
+                    .into_iter()
+                    .map(|s| s.to_string())
+                    .collect();
+                self.data_section()?.push((span, DataItem::Words(words)));
+            }
+            // .string "TEXT"
+            // Bytes of TEXT, padded with zeros to a multiple of the word size.
+            "string" => {
+                let bytes = parse_string(args)?;
+                self.data_section()?.push((span, DataItem::Bytes(bytes)));
+            }
+            // .space BYTES
+            "space" => {
+                let bytes = scope.parse_value(args)?;
+                let words = bytes.div_ceil(WORD_SIZE) as usize;
+                let zeros = DataItem::Words(vec!["0".to_string(); words]);
+                self.data_section()?.push((span, zeros));
+            }
+
+            // .include "PATH" [as NAME]
+            // Makes the symbols exported by the module at PATH available as NAME.symbol, NAME
+            // defaulting to the file name without extension.
+            "include" => {
+                let (path, alias) = match args.rsplit_once(" as ") {
+                    Some((path, alias)) => (path, Some(alias)),
+                    None => (args, None),
+                };
+                let path = path
+                    .strip_prefix('"')
+                    .and_then(|p| p.strip_suffix('"'))
+                    .ok_or_eyre(format!("Expected quoted path: {path}"))?;
+                let path = self.dir.join(path);
+                let alias = match alias {
+                    Some(a) => a.to_string(),
+                    None => path
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .ok_or_eyre(format!("No module name for {}", path.display()))?,
+                };
+
+                let index = linker.load(&path)?;
+                self.symbols.import(&alias, index)?;
+            }
+            // .export NAME, ...
+            "export" => {
+                for name in split_args(args) {
+                    let span = span.narrow(&linker.sources, name);
+                    self.symbols.exports.insert(name.to_string(), span);
+                }
+            }
+
+            _ => eyre::bail!("Unknown directive: .{name}"),
+        }
+
+        Ok(())
+    }
+
+    // .macro NAME param, ...
+    // <body>
+    // .endmacro
+    fn define_macro(&mut self, signature: &str, body: Vec<Line>) -> eyre::Result<()> {
+        let (name, params) = split_command(signature);
+        eyre::ensure!(is_identifier(name), "Invalid macro name: {name:?}");
+        eyre::ensure!(
+            !OpCode::NAMES.contains(&name),
+            "Macro name shadows command: {name}"
+        );
+        for param in &params {
+            eyre::ensure!(is_identifier(param), "Invalid macro parameter: {param:?}");
+        }
+
+        let existing = self.macros.insert(
+            name.to_string(),
+            Macro {
+                params: params.into_iter().map(|p| p.to_string()).collect(),
+                body,
+            },
+        );
+        eyre::ensure!(existing.is_none(), "Duplicate macro: {name}");
+        Ok(())
+    }
+
+    /// Substitutes `%param`s in the macro body, renaming labels defined in the body so each
+    /// expansion gets its own.
+    fn invoke_macro(&mut self, name: &str, args: &[&str]) -> eyre::Result<Vec<Line>> {
+        let macro_ = &self.macros[name];
+        eyre::ensure!(
+            args.len() == macro_.params.len(),
+            "Macro {name} expects {} arguments, got {}",
+            macro_.params.len(),
+            args.len()
+        );
+
+        self.expansion_count += 1;
+        let suffix = format!("@{name}{}", self.expansion_count);
+
+        let locals = macro_
+            .body
+            .iter()
+            .filter_map(|l| l.text.strip_prefix(":"))
+            .collect::<Vec<_>>();
+        let params = macro_
+            .params
+            .iter()
+            .map(|p| p.as_str())
+            .zip(args.iter().copied())
+            .collect::<HashMap<_, _>>();
+
+        macro_
+            .body
+            .iter()
+            .map(|line| {
+                let text = replace_names(&line.text, ':', |label| {
+                    locals.contains(&label).then(|| format!(":{label}{suffix}"))
+                });
+                let mut unknown = None;
+                let text = replace_names(&text, '%', |param| match params.get(param) {
+                    Some(arg) => Some(arg.to_string()),
+                    None => {
+                        unknown.get_or_insert(param.to_string());
+                        None
+                    }
+                });
+                match unknown {
+                    Some(param) => eyre::bail!("Unknown macro parameter: %{param}"),
+                    None => Ok(Line {
+                        text,
+                        span: line.span,
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Replaces every `{sigil}name` in `s` for which `replacement` returns `Some`.
+fn replace_names(
+    s: &str,
+    sigil: char,
+    mut replacement: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(sigil) {
+        result.push_str(&rest[..start]);
+
+        let after = &rest[start + sigil.len_utf8()..];
+        let len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '@'))
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        match (!name.is_empty()).then(|| replacement(name)).flatten() {
+            Some(r) => result.push_str(&r),
+            None => {
+                result.push(sigil);
+                result.push_str(name);
+            }
+        }
+
+        rest = &after[len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Names that can be used in place of literal values.
+#[derive(Debug, Default)]
+pub(crate) struct Symbols {
+    labels: HashMap<String, Word>,
+    /// Bytes of data following each data label.
+    sizes: HashMap<String, Word>,
+    constants: HashMap<String, Word>,
+    exports: BTreeMap<String, Span>,
+    imports: HashMap<String, usize>,
+    laid_out: bool,
+}
+
+impl Symbols {
+    fn define_label(&mut self, label: &str, value: Word) -> eyre::Result<()> {
+        let existing = self.labels.insert(label.to_string(), value);
+        eyre::ensure!(existing.is_none(), "Duplicate label: {label}");
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, value: Word) -> eyre::Result<()> {
+        eyre::ensure!(is_identifier(name), "Invalid constant name: {name:?}");
+        let existing = self.constants.insert(name.to_string(), value);
+        eyre::ensure!(existing.is_none(), "Duplicate constant: {name}");
+        Ok(())
+    }
+
+    fn import(&mut self, alias: &str, module: usize) -> eyre::Result<()> {
+        eyre::ensure!(is_identifier(alias), "Invalid module name: {alias:?}");
+        let existing = self.imports.insert(alias.to_string(), module);
+        eyre::ensure!(existing.is_none(), "Duplicate module: {alias}");
+        Ok(())
+    }
+
+    fn defines(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.constants.contains_key(name)
+    }
+
+    fn label(&self, label: &str) -> eyre::Result<Word> {
+        eyre::ensure!(
+            self.laid_out,
+            "Label :{label} used before layout, labels can't be used in .equ, .define or .space"
+        );
+        self.labels
+            .get(label)
+            .copied()
+            .ok_or_eyre(format!("Unknown label: {label}"))
+    }
+
+    fn size_of(&self, label: &str) -> eyre::Result<Word> {
+        self.label(label)?;
+        self.sizes
+            .get(label)
+            .copied()
+            .ok_or_eyre(format!("sizeof requires a data label: :{label}"))
+    }
+
+    fn constant(&self, name: &str) -> eyre::Result<Word> {
+        self.constants
+            .get(name)
+            .copied()
+            .ok_or_eyre(format!("Unknown constant: {name}"))
+    }
+}
+
+/// Symbols visible from one module: its own, and those exported by modules it includes.
+pub(crate) struct Scope<'a> {
+    symbols: &'a Symbols,
+    modules: &'a [Module],
+}
+
+impl Scope<'_> {
+    /// Finds the module that defines a possibly `module.`-qualified name.
+    fn resolve<'n>(&self, name: &'n str) -> eyre::Result<(&Symbols, &'n str)> {
+        let Some((alias, name)) = name.split_once(".") else {
+            return Ok((self.symbols, name));
+        };
+
+        let index = self
+            .symbols
+            .imports
+            .get(alias)
+            .ok_or_eyre(format!("Unknown module: {alias}"))?;
+        let symbols = &self.modules[*index].symbols;
+        eyre::ensure!(
+            symbols.exports.contains_key(name),
+            "{name} is not exported by {alias}"
+        );
+        Ok((symbols, name))
+    }
+
+    fn label(&self, label: &str) -> eyre::Result<Word> {
+        let (symbols, label) = self.resolve(label)?;
+        symbols.label(label)
+    }
+
+    fn constant(&self, name: &str) -> eyre::Result<Word> {
+        let (symbols, name) = self.resolve(name)?;
+        symbols.constant(name)
+    }
+
+    fn size_of(&self, label: &str) -> eyre::Result<Word> {
+        let (symbols, label) = self.resolve(label)?;
+        symbols.size_of(label)
+    }
+
+    /// Parses a value that must be known while expanding directives.
+    fn parse_value(&self, s: &str) -> eyre::Result<Word> {
+        match ValSp::parse(s, self).context(format!("Parsing {s}"))? {
+            ValSp::Literal(v) => Ok(v),
+            _ => eyre::bail!("Expected a constant value: {s}"),
+        }
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl ValSp {
+    pub(crate) fn parse(s: &str, scope: &Scope) -> eyre::Result<ValSp> {
+        expr::parse_operand(s)?.resolve(scope)
+    }
+}
+
+fn parse_literal(s: &str) -> eyre::Result<Word> {
+    // Floats are stored as their bit pattern.
+    if s.contains('.') {
+        let v: f64 = s.parse().context(format!("Parsing as float: {s:?}"))?;
+        return Ok(v.to_bits());
+    }
+
+    if let Some(negated) = s.strip_prefix("-") {
+        let v = parse_literal(negated)?;
+        eyre::ensure!(v <= i64::MIN.unsigned_abs(), "Literal out of range: {s:?}");
+        return Ok(v.wrapping_neg());
+    }
+
+    if let Some(hex) = s.strip_prefix("0x") {
+        let v = Word::from_str_radix(hex, 16).context(format!("Parsing as hex: {hex:?}"))?;
+        return Ok(v);
+    }
+
+    if let Ok(v) = s.parse() {
+        return Ok(v);
+    }
+
+    eyre::bail!("Could not parse as literal value: {s:?}")
+}
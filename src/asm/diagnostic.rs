@@ -0,0 +1,121 @@
+use std::fmt::{self, Display, Write as _};
+
+/// A flasm file that was assembled into a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Source {
+    pub(crate) name: String,
+    pub(crate) text: String,
+}
+
+/// Location of some text in a [`Source`]. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub(crate) source: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) len: usize,
+}
+
+impl Span {
+    pub(crate) fn location(&self, sources: &[Source]) -> String {
+        let name = sources
+            .get(self.source)
+            .map(|s| s.name.as_str())
+            .unwrap_or("<unknown>");
+        format!("{name}:{}:{}", self.line, self.column)
+    }
+
+    pub(crate) fn source_line<'s>(&self, sources: &'s [Source]) -> Option<&'s str> {
+        sources.get(self.source)?.text.lines().nth(self.line - 1)
+    }
+
+    /// The part of this span that is `part`, or the whole span if it can't be found, like when
+    /// `part` came from a macro parameter.
+    pub(crate) fn narrow(&self, sources: &[Source], part: &str) -> Span {
+        let found = self.source_line(sources).and_then(|line| {
+            line.get(self.column - 1..self.column - 1 + self.len)?
+                .find(part)
+        });
+        match found {
+            Some(offset) if !part.is_empty() => Span {
+                column: self.column + offset,
+                len: part.len(),
+                ..*self
+            },
+            _ => *self,
+        }
+    }
+
+    /// Renders `message` pointing at this span, like:
+    ///
+    /// ```text
+    /// error: Unknown command: FOO
+    ///  --> example.flasm:3:1
+    ///   |
+    /// 3 | FOO 1, 2
+    ///   | ^^^
+    /// ```
+    pub(crate) fn render(&self, sources: &[Source], message: impl Display) -> String {
+        format!("error: {message}\n{}", self.snippet(sources))
+    }
+
+    /// The location and source line of this span, without a message.
+    pub(crate) fn snippet(&self, sources: &[Source]) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        let mut s = String::new();
+        let _ = writeln!(s, "{gutter}--> {}", self.location(sources));
+        if let Some(line) = self.source_line(sources) {
+            let _ = writeln!(s, "{gutter} |");
+            let _ = writeln!(s, "{} | {line}", self.line);
+            let _ = writeln!(
+                s,
+                "{gutter} | {}{}",
+                " ".repeat(self.column - 1),
+                "^".repeat(self.len.max(1))
+            );
+        }
+        s
+    }
+}
+
+/// Every error found while assembling or compiling a program.
+#[derive(Debug)]
+pub(crate) struct ParseErrors {
+    /// What couldn't be done, like "assemble".
+    action: &'static str,
+    rendered: Vec<String>,
+}
+
+impl ParseErrors {
+    /// Renders `errors` in the order they appear in `sources`.
+    pub(crate) fn new(
+        action: &'static str,
+        sources: &[Source],
+        mut errors: Vec<(Span, eyre::Report)>,
+    ) -> ParseErrors {
+        errors.sort_by_key(|(span, _)| (span.source, span.line, span.column));
+        let rendered = errors
+            .iter()
+            .map(|(span, e)| span.render(sources, format!("{e:#}")))
+            .collect();
+        ParseErrors { action, rendered }
+    }
+}
+
+impl Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rendered in &self.rendered {
+            writeln!(f, "{rendered}")?;
+        }
+        write!(
+            f,
+            "could not {} due to {} error{}",
+            self.action,
+            self.rendered.len(),
+            if self.rendered.len() == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl std::error::Error for ParseErrors {}
@@ -0,0 +1,125 @@
+//! Renders programs back into flasm.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+use crate::{to_global, OpCode, Program, ValSp, Word, DATA_BASE, WORD_SIZE};
+
+impl Program {
+    /// Renders this program as flasm that parses back into an equal program.
+    ///
+    /// Symbols don't survive assembly, so jump targets get synthesized labels and everything else
+    /// is a literal. Fails if the program has data the assembler couldn't have placed, below the
+    /// start of its section or between words, which only hand-made bytecode can.
+    pub fn disassemble(&self) -> eyre::Result<String> {
+        let targets = self
+            .ops
+            .iter()
+            .flat_map(jump_targets)
+            .filter(|&t| t <= self.ops.len() as Word)
+            .collect::<BTreeSet<_>>();
+
+        let mut s = String::new();
+        for (i, op) in (0..).zip(&self.ops) {
+            if targets.contains(&i) {
+                let _ = writeln!(s, ":L{i}");
+            }
+            let _ = writeln!(s, "{}", disassemble_op(op, &targets));
+        }
+        // A jump to the end of the program.
+        if targets.contains(&(self.ops.len() as Word)) {
+            let _ = writeln!(s, ":L{}", self.ops.len());
+        }
+
+        disassemble_data(&mut s, ".data", DATA_BASE, &self.data)?;
+        disassemble_data(&mut s, ".gdata", to_global(DATA_BASE), &self.global_data)?;
+        Ok(s)
+    }
+}
+
+fn jump_targets(op: &OpCode) -> impl Iterator<Item = Word> + '_ {
+    op.arg_names()
+        .iter()
+        .zip(op.args())
+        .filter_map(|(&name, arg)| match (name, arg) {
+            ("target", ValSp::Literal(t)) => Some(*t),
+            _ => None,
+        })
+}
+
+fn disassemble_op(op: &OpCode, targets: &BTreeSet<Word>) -> String {
+    let args = op
+        .arg_names()
+        .iter()
+        .zip(op.args())
+        .map(|(&name, arg)| match (name, arg) {
+            ("target", ValSp::Literal(t)) if targets.contains(t) => format!(":L{t}"),
+            _ => arg.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if args.is_empty() {
+        op.name().to_string()
+    } else {
+        format!("{} {}", op.name(), args.join(", "))
+    }
+}
+
+/// Writes `memory` as words starting from `base`, with `.space` for runs of zeros.
+fn disassemble_data(
+    s: &mut String,
+    section: &str,
+    base: Word,
+    memory: &BTreeMap<Word, Word>,
+) -> eyre::Result<()> {
+    if memory.is_empty() {
+        return Ok(());
+    }
+
+    let _ = writeln!(s, "{section}");
+    let mut addr = base;
+    let mut words = Vec::new();
+    for (&a, &v) in memory {
+        eyre::ensure!(
+            a >= addr && (a - base).is_multiple_of(WORD_SIZE),
+            "Can't disassemble {section} word at 0x{a:x}, {section} is placed from 0x{base:x}"
+        );
+        if a != addr {
+            if !words.is_empty() {
+                let _ = writeln!(s, ".word {}", words.join(", "));
+                words.clear();
+            }
+            let _ = writeln!(s, ".space {}", a - addr);
+        }
+        words.push(literal(v));
+        addr = a + WORD_SIZE;
+    }
+    let _ = writeln!(s, ".word {}", words.join(", "));
+    Ok(())
+}
+
+fn literal(v: Word) -> String {
+    if v < 1 << 16 {
+        v.to_string()
+    } else {
+        format!("0x{v:x}")
+    }
+}
+
+impl std::fmt::Display for ValSp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValSp::Literal(v) => write!(f, "{}", literal(*v)),
+            ValSp::Pop => write!(f, "$pop"),
+            ValSp::PopI(i) => write!(f, "$pop[{i}]"),
+            ValSp::Peek => write!(f, "$peek"),
+            ValSp::Memory(addr) => write!(f, "$mem[{addr}]"),
+            ValSp::GlobalMemory(addr) => write!(f, "$gmem[{addr}]"),
+            ValSp::Frame(offset) => write!(f, "$frame[{offset}]"),
+            ValSp::FramePointer => write!(f, "$fp"),
+            ValSp::ThreadId => write!(f, "$tid"),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Operands like `$mem[$pop[1]]`, and the constant expressions in them like `:table + 8 * 3` or
+//! `ADDR_BASE | 0x10`.
+
+use eyre::OptionExt;
+
+use super::{parse_literal, Scope};
+use crate::{ValSp, Word, MAX_OPERAND_DEPTH, WORD_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'s> {
+    Number(&'s str),
+    Label(&'s str),
+    Symbol(&'s str),
+    /// A runtime value like `$pop`, without the `$`.
+    Register(&'s str),
+    Op(&'s str),
+}
+
+const OPS: &[&str] = &[
+    "<<", ">>", "+", "-", "*", "/", "%", "|", "&", "^", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(s: &str) -> eyre::Result<Vec<Token<'_>>> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@';
+
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            ':' | '$' => {
+                1 + rest[1..]
+                    .find(|c| !is_name_char(c))
+                    .unwrap_or(rest.len() - 1)
+            }
+            _ if is_name_char(c) => rest.find(|c| !is_name_char(c)).unwrap_or(rest.len()),
+            _ => OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .map(|op| op.len())
+                .ok_or_eyre(format!("Unexpected {c:?} in {s}"))?,
+        };
+
+        let (token, after) = rest.split_at(len);
+        tokens.push(match c {
+            ':' => Token::Label(&token[1..]),
+            '$' => Token::Register(&token[1..]),
+            _ if c.is_ascii_digit() => Token::Number(token),
+            _ if is_name_char(c) => Token::Symbol(token),
+            _ => Token::Op(token),
+        });
+        rest = after.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// An operand, before its labels and constants are resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Operand<'s> {
+    Value(Expr<'s>),
+    Pop,
+    PopI(Box<Operand<'s>>),
+    Peek,
+    Memory(Box<Operand<'s>>),
+    GlobalMemory(Box<Operand<'s>>),
+    Frame(Box<Operand<'s>>),
+    FramePointer,
+    ThreadId,
+}
+
+/// A value known while assembling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Expr<'s> {
+    Number(&'s str),
+    Label(&'s str),
+    Constant(&'s str),
+    /// sizeof(:label)
+    /// Bytes of data from the label up to the next label in its section.
+    SizeOf(&'s str),
+    Unary(&'s str, Box<Expr<'s>>),
+    Binary(&'s str, Box<Expr<'s>>, Box<Expr<'s>>),
+}
+
+pub(super) fn parse_operand(s: &str) -> eyre::Result<Operand<'_>> {
+    let tokens = tokenize(s)?;
+    eyre::ensure!(!tokens.is_empty(), "Expected a value");
+
+    let mut parser = Parser {
+        source: s,
+        tokens: &tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let operand = parser.operand()?;
+    match parser.peek() {
+        None => Ok(operand),
+        Some(Token::Op("]")) => eyre::bail!("Unmatched ']' in {s}"),
+        Some(Token::Op(op)) if precedence(op).is_some() => {
+            eyre::bail!("Runtime value in constant expression: {s}")
+        }
+        Some(token) => eyre::bail!("Unexpected {token:?} in {s}"),
+    }
+}
+
+impl Operand<'_> {
+    pub(super) fn resolve(&self, scope: &Scope) -> eyre::Result<ValSp> {
+        let boxed = |o: &Operand| eyre::Ok(Box::new(o.resolve(scope)?));
+
+        Ok(match self {
+            Operand::Value(expr) => ValSp::Literal(expr.eval(scope)?),
+            Operand::Pop => ValSp::Pop,
+            Operand::PopI(i) => ValSp::PopI(boxed(i)?),
+            Operand::Peek => ValSp::Peek,
+            Operand::Memory(addr) => ValSp::Memory(boxed(addr)?),
+            Operand::GlobalMemory(addr) => ValSp::GlobalMemory(boxed(addr)?),
+            Operand::Frame(offset) => ValSp::Frame(boxed(offset)?),
+            Operand::FramePointer => ValSp::FramePointer,
+            Operand::ThreadId => ValSp::ThreadId,
+        })
+    }
+}
+
+impl Expr<'_> {
+    pub(super) fn eval(&self, scope: &Scope) -> eyre::Result<Word> {
+        match self {
+            Expr::Number(n) => parse_literal(n),
+            Expr::Label(label) => scope.label(label),
+            Expr::Constant(name) => scope.constant(name),
+            Expr::SizeOf(label) => scope.size_of(label),
+
+            // Negative literals are parsed whole, so that `-1.5` is a float.
+            Expr::Unary("-", inner) => match **inner {
+                Expr::Number(n) => parse_literal(&format!("-{n}")),
+                _ => Ok(inner.eval(scope)?.wrapping_neg()),
+            },
+            Expr::Unary(_, inner) => Ok(!inner.eval(scope)?),
+
+            Expr::Binary(op, a, b) => apply(op, a.eval(scope)?, b.eval(scope)?),
+        }
+    }
+}
+
+struct Parser<'t, 's> {
+    source: &'s str,
+    tokens: &'t [Token<'s>],
+    pos: usize,
+    /// Brackets around the operand being parsed.
+    depth: usize,
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+fn apply(op: &str, a: Word, b: Word) -> eyre::Result<Word> {
+    let shift = |b: Word| {
+        eyre::ensure!(b < WORD_SIZE * 8, "Shift amount out of range: {b}");
+        Ok(b as u32)
+    };
+
+    let result = match op {
+        "|" => Some(a | b),
+        "^" => Some(a ^ b),
+        "&" => Some(a & b),
+        "<<" => Some(a << shift(b)?),
+        ">>" => Some(a >> shift(b)?),
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" | "%" if b == 0 => eyre::bail!("Division by zero: {a} {op} {b}"),
+        "/" => a.checked_div(b),
+        "%" => a.checked_rem(b),
+        _ => unreachable!("Not a binary operator: {op}"),
+    };
+    result.ok_or_eyre(format!("Overflow: {a} {op} {b}"))
+}
+
+impl<'s> Parser<'_, 's> {
+    fn peek(&self) -> Option<Token<'s>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'s>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> eyre::Result<()> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            other => eyre::bail!("Expected {op:?}, found {other:?}"),
+        }
+    }
+
+    fn operand(&mut self) -> eyre::Result<Operand<'s>> {
+        let Some(Token::Register(register)) = self.peek() else {
+            return Ok(Operand::Value(self.binary(0)?));
+        };
+        self.pos += 1;
+
+        let indexed = self.peek() == Some(Token::Op("["));
+        Ok(match register {
+            "pop" if indexed => Operand::PopI(self.index()?),
+            "pop" => Operand::Pop,
+            "peek" => Operand::Peek,
+            "fp" => Operand::FramePointer,
+            "tid" => Operand::ThreadId,
+
+            "mem" | "gmem" | "frame" if !indexed => eyre::bail!("${register} requires index"),
+            "mem" => Operand::Memory(self.index()?),
+            "gmem" => Operand::GlobalMemory(self.index()?),
+            "frame" => Operand::Frame(self.index()?),
+
+            _ => eyre::bail!("Unknown register: ${register}"),
+        })
+    }
+
+    /// `[operand]`
+    fn index(&mut self) -> eyre::Result<Box<Operand<'s>>> {
+        self.expect("[")?;
+        eyre::ensure!(
+            self.depth + 1 < MAX_OPERAND_DEPTH,
+            "Operand nested too deeply: {}",
+            self.source
+        );
+        self.depth += 1;
+        let operand = self.operand()?;
+        self.depth -= 1;
+        match self.next() {
+            Some(Token::Op("]")) => Ok(Box::new(operand)),
+            None => eyre::bail!("Unclosed '[' in {}", self.source),
+            Some(token) => eyre::bail!("Expected ']', found {token:?} in {}", self.source),
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> eyre::Result<Expr<'s>> {
+        let mut lhs = self.unary()?;
+
+        while let Some(Token::Op(op)) = self.peek() {
+            let Some(precedence) = precedence(op) else {
+                break;
+            };
+            if precedence < min_precedence {
+                break;
+            }
+
+            self.pos += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> eyre::Result<Expr<'s>> {
+        match self.next() {
+            Some(Token::Op(op @ ("-" | "~"))) => Ok(Expr::Unary(op, Box::new(self.unary()?))),
+            Some(Token::Op("(")) => {
+                let value = self.binary(0)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Label(label)) => Ok(Expr::Label(label)),
+            Some(Token::Register(_)) => {
+                eyre::bail!("Runtime value in constant expression: {}", self.source)
+            }
+
+            Some(Token::Symbol("sizeof")) if self.peek() == Some(Token::Op("(")) => {
+                self.expect("(")?;
+                let Some(Token::Label(label)) = self.next() else {
+                    eyre::bail!("sizeof requires a label");
+                };
+                self.expect(")")?;
+                Ok(Expr::SizeOf(label))
+            }
+            Some(Token::Symbol(name)) => Ok(Expr::Constant(name)),
+
+            other => eyre::bail!("Expected a value, found {other:?}"),
+        }
+    }
+}
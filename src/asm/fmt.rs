@@ -0,0 +1,55 @@
+//! Canonical formatting of flasm source.
+//!
+//! Lines are split into code and comments the way the assembler splits them, so formatting never
+//! changes what a program assembles to. Code is unindented, commands are separated from their
+//! arguments by one space and arguments by `", "`, trailing comments are separated from code by
+//! one space, and runs of blank lines are collapsed.
+
+use super::{split_command, strip_comment, Line};
+
+/// Formats flasm source, keeping its comments.
+pub fn format_flasm(s: &str) -> String {
+    let mut lines = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let comment = line[strip_comment(line).len()..].trim();
+        let Some(code) = Line::parse(0, i, line) else {
+            lines.push(comment.to_string());
+            continue;
+        };
+
+        let mut formatted = format_code(&code.text);
+        if !comment.is_empty() {
+            formatted.push(' ');
+            formatted.push_str(comment);
+        }
+        lines.push(formatted);
+    }
+
+    let mut result = String::new();
+    let mut blank = false;
+    for line in lines {
+        if line.is_empty() {
+            blank = !result.is_empty();
+            continue;
+        }
+        if blank {
+            result.push('\n');
+            blank = false;
+        }
+        result.push_str(&line);
+        result.push('\n');
+    }
+    result
+}
+
+fn format_code(code: &str) -> String {
+    // The assembler reads the whole line as the label's name.
+    if code.starts_with(':') {
+        return code.to_string();
+    }
+
+    match split_command(code) {
+        (command, args) if args.is_empty() => command.to_string(),
+        (command, args) => format!("{command} {}", args.join(", ")),
+    }
+}
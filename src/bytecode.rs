@@ -0,0 +1,327 @@
+//! Binary encoding of programs, stored in `.flbc` files.
+//!
+//! A file is a header followed by the encoded program:
+//!
+//! ```text
+//! magic    b"FLBC"
+//! version  u16
+//! hash     u64, seahash of everything after the header
+//! ops      u64 count, then per op: u16 index into OpCode::NAMES, u8 argument count, arguments
+//! data     u64 count, then (address, value) word pairs
+//! gdata    u64 count, then (address, value) word pairs
+//! ```
+//!
+//! Numbers are little-endian. Source spans aren't encoded, so traps in a loaded program don't
+//! point at a source line.
+
+use std::{collections::BTreeMap, path::Path};
+
+use eyre::{Context as _, OptionExt};
+
+use crate::{OpCode, Program, ValSp, Word, MAX_OPERAND_DEPTH, WORD_SIZE};
+
+const MAGIC: &[u8; 4] = b"FLBC";
+/// Opcodes are encoded by their position in `OpCode::NAMES`, so this must change whenever that
+/// list does.
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+
+impl Program {
+    /// Encodes this program as `.flbc` bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = self.encode_body();
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend(MAGIC);
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(seahash::hash(&body).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Decodes a program encoded with [`Program::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> eyre::Result<Program> {
+        let mut reader = Reader { bytes };
+        eyre::ensure!(
+            reader.take(MAGIC.len())? == MAGIC,
+            "Not flock bytecode, missing magic"
+        );
+        let version = reader.u16()?;
+        eyre::ensure!(
+            version == VERSION,
+            "Unsupported bytecode version {version}, expected {VERSION}"
+        );
+        let hash = reader.word()?;
+        eyre::ensure!(
+            seahash::hash(reader.bytes) == hash,
+            "Bytecode hash mismatch, the file is corrupt"
+        );
+
+        let op_count = reader.word()?;
+        let ops = (0..op_count)
+            .map(|i| reader.op().context(format!("Decoding op {i}")))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let data = reader.memory().context("Decoding data")?;
+        let global_data = reader.memory().context("Decoding global data")?;
+        eyre::ensure!(reader.bytes.is_empty(), "Trailing bytes after program");
+
+        Ok(Program {
+            ops,
+            spans: Vec::new(),
+            sources: Vec::new(),
+            data,
+            global_data,
+        })
+    }
+
+    /// Identifies the program by its code and data, ignoring where it was parsed from.
+    pub fn hash(&self) -> u64 {
+        seahash::hash(&self.encode_body())
+    }
+
+    /// Loads a program from a `.flbc`, `.fl` or flasm file.
+    pub fn load_file(path: &Path) -> eyre::Result<Program> {
+        let bytes = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        if bytes.starts_with(MAGIC) {
+            Program::from_bytes(&bytes).with_context(|| format!("Loading {}", path.display()))
+        } else if path.extension().is_some_and(|e| e == "fl") {
+            Program::compile_file(path)
+        } else {
+            Program::parse_file(path)
+        }
+    }
+
+    fn encode_body(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend((self.ops.len() as Word).to_le_bytes());
+        for op in &self.ops {
+            let tag = OpCode::NAMES
+                .iter()
+                .position(|&n| n == op.name())
+                .expect("op name is in NAMES") as u16;
+            bytes.extend(tag.to_le_bytes());
+
+            let args = op.args();
+            bytes.push(args.len() as u8);
+            for arg in args {
+                encode_val_sp(arg, &mut bytes);
+            }
+        }
+
+        for memory in [&self.data, &self.global_data] {
+            bytes.extend((memory.len() as Word).to_le_bytes());
+            for (addr, value) in memory {
+                bytes.extend(addr.to_le_bytes());
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+}
+
+fn encode_val_sp(val_sp: &ValSp, bytes: &mut Vec<u8>) {
+    match val_sp {
+        ValSp::Literal(v) => {
+            bytes.push(0);
+            bytes.extend(v.to_le_bytes());
+        }
+        ValSp::Pop => bytes.push(1),
+        ValSp::PopI(i) => {
+            bytes.push(2);
+            encode_val_sp(i, bytes);
+        }
+        ValSp::Peek => bytes.push(3),
+        ValSp::Memory(addr) => {
+            bytes.push(4);
+            encode_val_sp(addr, bytes);
+        }
+        ValSp::GlobalMemory(addr) => {
+            bytes.push(5);
+            encode_val_sp(addr, bytes);
+        }
+        ValSp::Frame(offset) => {
+            bytes.push(6);
+            encode_val_sp(offset, bytes);
+        }
+        ValSp::FramePointer => bytes.push(7),
+        ValSp::ThreadId => bytes.push(8),
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> eyre::Result<&'b [u8]> {
+        eyre::ensure!(self.bytes.len() >= n, "Unexpected end of bytecode");
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> eyre::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> eyre::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn word(&mut self) -> eyre::Result<Word> {
+        Ok(Word::from_le_bytes(
+            self.take(WORD_SIZE as usize)?.try_into()?,
+        ))
+    }
+
+    fn op(&mut self) -> eyre::Result<OpCode> {
+        let tag = self.u16()?;
+        let name = OpCode::NAMES
+            .get(tag as usize)
+            .ok_or_eyre(format!("Unknown opcode tag: {tag}"))?;
+        let arg_count = self.u8()?;
+        let args = (0..arg_count)
+            .map(|_| self.val_sp(0))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        OpCode::from_args(name, args)
+    }
+
+    fn val_sp(&mut self, depth: usize) -> eyre::Result<ValSp> {
+        eyre::ensure!(depth < MAX_OPERAND_DEPTH, "Operand nested too deeply");
+        Ok(match self.u8()? {
+            0 => ValSp::Literal(self.word()?),
+            1 => ValSp::Pop,
+            2 => ValSp::PopI(Box::new(self.val_sp(depth + 1)?)),
+            3 => ValSp::Peek,
+            4 => ValSp::Memory(Box::new(self.val_sp(depth + 1)?)),
+            5 => ValSp::GlobalMemory(Box::new(self.val_sp(depth + 1)?)),
+            6 => ValSp::Frame(Box::new(self.val_sp(depth + 1)?)),
+            7 => ValSp::FramePointer,
+            8 => ValSp::ThreadId,
+            tag => eyre::bail!("Unknown operand tag: {tag}"),
+        })
+    }
+
+    fn memory(&mut self) -> eyre::Result<BTreeMap<Word, Word>> {
+        let count = self.word()?;
+        (0..count)
+            .map(|_| Ok((self.word()?, self.word()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{to_global, DATA_BASE};
+
+    const RANDOM_PROGRAMS: usize = 1000;
+
+    /// Random programs survive encoding and disassembly.
+    #[test]
+    fn random_programs() {
+        let seed = ::rand::random();
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..RANDOM_PROGRAMS {
+            let program = random_program(&mut rng);
+
+            let decoded = Program::from_bytes(&program.to_bytes()).unwrap();
+            assert_eq!(decoded, program, "With seed {seed}");
+
+            let placeable = program.data.keys().all(|&a| a >= DATA_BASE)
+                && program
+                    .global_data
+                    .keys()
+                    .all(|&a| a >= to_global(DATA_BASE));
+            match program.disassemble() {
+                Ok(flasm) => {
+                    assert!(placeable, "With seed {seed}, misplaced data in:\n{flasm}");
+                    let parsed = Program::parse(&flasm)
+                        .unwrap_or_else(|e| panic!("With seed {seed}, parsing:\n{flasm}\n{e:?}"));
+                    assert_eq!(
+                        parsed, program,
+                        "With seed {seed}, disassembled as:\n{flasm}"
+                    );
+                }
+                Err(e) => assert!(!placeable, "With seed {seed}: {e:?}"),
+            }
+        }
+    }
+
+    fn random_program(rng: &mut StdRng) -> Program {
+        let op_count = rng.gen_range(0..20);
+        Program {
+            ops: (0..op_count).map(|_| random_op(rng, op_count)).collect(),
+            spans: Vec::new(),
+            sources: Vec::new(),
+            data: random_data(rng, DATA_BASE),
+            global_data: random_data(rng, to_global(DATA_BASE)),
+        }
+    }
+
+    fn random_op(rng: &mut StdRng, op_count: usize) -> OpCode {
+        let op = rng.gen_range(0..OpCode::NAMES.len());
+        let args = OpCode::ARG_NAMES[op]
+            .iter()
+            .map(|&name| match name {
+                "target" if rng.gen() => ValSp::Literal(rng.gen_range(0..=op_count as Word)),
+                _ => random_val_sp(rng, 3),
+            })
+            .collect();
+        OpCode::from_args(OpCode::NAMES[op], args).unwrap()
+    }
+
+    /// An operand of any kind, nesting at most `depth` deep.
+    fn random_val_sp(rng: &mut StdRng, depth: usize) -> ValSp {
+        let nested = |rng: &mut StdRng| Box::new(random_val_sp(rng, depth - 1));
+        match rng.gen_range(0..10) {
+            0 => ValSp::Pop,
+            1 => ValSp::Peek,
+            2 => ValSp::FramePointer,
+            3 => ValSp::ThreadId,
+            4 if depth > 0 => ValSp::PopI(nested(rng)),
+            5 if depth > 0 => ValSp::Memory(nested(rng)),
+            6 if depth > 0 => ValSp::GlobalMemory(nested(rng)),
+            7 if depth > 0 => ValSp::Frame(nested(rng)),
+            _ => ValSp::Literal(match rng.gen_range(0..3) {
+                0 => rng.gen_range(0..1 << 16),
+                1 => rng.gen_range(0..8 as Word).wrapping_neg(),
+                _ => rng.gen(),
+            }),
+        }
+    }
+
+    /// Data as the assembler lays it out, nonzero words from the base of a section, and
+    /// sometimes a word below the base that only hand-made bytecode could have.
+    fn random_data(rng: &mut StdRng, base: Word) -> BTreeMap<Word, Word> {
+        let mut addr = base;
+        let mut data = (0..rng.gen_range(0..4))
+            .map(|_| {
+                addr += WORD_SIZE * rng.gen_range(0..3);
+                let word = (addr, rng.gen_range(1..=Word::MAX));
+                addr += WORD_SIZE;
+                word
+            })
+            .collect::<BTreeMap<_, _>>();
+        if rng.gen_ratio(1, 10) {
+            data.insert(
+                base - WORD_SIZE * rng.gen_range(1..4),
+                rng.gen_range(1..=Word::MAX),
+            );
+        }
+        data
+    }
+
+    #[test]
+    fn deep_operand() {
+        let mut bytes = vec![4; 100_000];
+        bytes.push(0);
+        bytes.extend(0u64.to_le_bytes());
+        let err = Reader { bytes: &bytes }.val_sp(0).unwrap_err();
+        assert_eq!(err.to_string(), "Operand nested too deeply");
+    }
+}
@@ -0,0 +1,184 @@
+//! Channels, bounded queues of words that threads send to and receive from.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+
+use eyre::OptionExt;
+use tokio::sync::{mpsc, oneshot, Mutex, Semaphore};
+
+use crate::{
+    rand::Rand,
+    remote::{Message, Peer, Peers},
+    ProcessCtx, Word,
+};
+
+pub(crate) struct Channels {
+    rand: Rand,
+    create_count: AtomicU64,
+    /// Channels on this host, until their process ends.
+    channels: Mutex<HashMap<Word, Arc<Channel>>>,
+    peers: Arc<Peers>,
+}
+
+struct Channel {
+    /// Only threads of this process can use the channel.
+    process: Weak<ProcessCtx>,
+    /// Taken when the channel is closed, so receivers see the end once the buffer is drained.
+    sender: Mutex<Option<mpsc::Sender<Word>>>,
+    receiver: Mutex<mpsc::Receiver<Word>>,
+}
+
+impl Channels {
+    pub(crate) fn new(rand: Rand, peers: Arc<Peers>) -> Channels {
+        Channels {
+            rand,
+            create_count: Default::default(),
+            channels: Default::default(),
+            peers,
+        }
+    }
+
+    pub(crate) async fn create(
+        &self,
+        capacity: Word,
+        process: &Arc<ProcessCtx>,
+    ) -> eyre::Result<Word> {
+        eyre::ensure!(
+            capacity > 0 && capacity <= Semaphore::MAX_PERMITS as Word,
+            "Invalid channel capacity: {capacity}"
+        );
+
+        let count = self.create_count.fetch_add(1, Ordering::Relaxed);
+        let channel = self.rand.get("channel_id").get(count.to_string()).word();
+
+        match self.owner(channel) {
+            None => {
+                let (sender, receiver) = mpsc::channel(capacity as usize);
+                let state = Channel {
+                    process: Arc::downgrade(process),
+                    sender: Mutex::new(Some(sender)),
+                    receiver: Mutex::new(receiver),
+                };
+                self.channels.lock().await.insert(channel, Arc::new(state));
+            }
+            Some(peer) => peer.send_message(Message::ChannelCreate {
+                process: process.id,
+                channel,
+                capacity,
+            })?,
+        }
+
+        Ok(channel)
+    }
+
+    /// Waits for room in the channel, then sends `value`.
+    pub(crate) async fn send(
+        &self,
+        channel: Word,
+        value: Word,
+        process: &Arc<ProcessCtx>,
+    ) -> eyre::Result<()> {
+        if let Some(peer) = self.owner(channel) {
+            let (reply, sent) = oneshot::channel();
+            peer.send_message(Message::ChannelSend {
+                process: process.id,
+                channel,
+                value,
+                reply,
+            })?;
+            eyre::ensure!(sent.await?, "Send on closed channel: {channel}");
+            return Ok(());
+        }
+
+        let sender = self
+            .local(channel, process)
+            .await?
+            .sender
+            .lock()
+            .await
+            .clone()
+            .ok_or_eyre(format!("Send on closed channel: {channel}"))?;
+        // The channel keeps its receiver, so sending only fails if the channel is gone.
+        sender
+            .send(value)
+            .await
+            .map_err(|_| eyre::eyre!("Send on closed channel: {channel}"))
+    }
+
+    /// Waits for a value, or `None` once the channel is closed and empty.
+    pub(crate) async fn recv(
+        &self,
+        channel: Word,
+        process: &Arc<ProcessCtx>,
+    ) -> eyre::Result<Option<Word>> {
+        if let Some(peer) = self.owner(channel) {
+            let (reply, value) = oneshot::channel();
+            peer.send_message(Message::ChannelRecv {
+                process: process.id,
+                channel,
+                reply,
+            })?;
+            return Ok(value.await?);
+        }
+
+        let channel = self.local(channel, process).await?;
+        let mut receiver = channel.receiver.lock().await;
+        Ok(receiver.recv().await)
+    }
+
+    pub(crate) async fn close(&self, channel: Word, process: &Arc<ProcessCtx>) -> eyre::Result<()> {
+        if let Some(peer) = self.owner(channel) {
+            let (reply, was_open) = oneshot::channel();
+            peer.send_message(Message::ChannelClose {
+                process: process.id,
+                channel,
+                reply,
+            })?;
+            eyre::ensure!(was_open.await?, "Channel already closed: {channel}");
+            return Ok(());
+        }
+
+        let sender = self
+            .local(channel, process)
+            .await?
+            .sender
+            .lock()
+            .await
+            .take();
+        eyre::ensure!(sender.is_some(), "Channel already closed: {channel}");
+        Ok(())
+    }
+
+    /// Drops the channels of an ended process.
+    pub(crate) async fn forget(&self, process: &Arc<ProcessCtx>) {
+        let process = Arc::downgrade(process);
+        self.channels
+            .lock()
+            .await
+            .retain(|_, channel| !channel.process.ptr_eq(&process));
+    }
+
+    /// The peer that holds `channel`, or `None` if this host does. Channels are spread across
+    /// hosts by id, like threads.
+    fn owner(&self, channel: Word) -> Option<&Peer> {
+        let locations = self.peers.len() + 1;
+        match (channel as usize) % locations {
+            0 => None,
+            peer => Some(&self.peers[peer - 1]),
+        }
+    }
+
+    async fn local(&self, channel: Word, process: &Arc<ProcessCtx>) -> eyre::Result<Arc<Channel>> {
+        let channels = self.channels.lock().await;
+        let channel = channels
+            .get(&channel)
+            .filter(|c| c.process.ptr_eq(&Arc::downgrade(process)))
+            .ok_or_eyre(format!("Unknown channel: {channel}"))?;
+        Ok(Arc::clone(channel))
+    }
+}
@@ -33,5 +33,6 @@ pub(crate) trait EventListener: Send + Sync + 'static {
 
 #[non_exhaustive]
 pub(crate) enum Event {
+    #[allow(unused)]
     Message(Message),
 }
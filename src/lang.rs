@@ -0,0 +1,86 @@
+//! Flock's structured language, compiled to flasm.
+//!
+//! ```text
+//! global counter = 0;
+//!
+//! fn square(n) {
+//!     return n * n;
+//! }
+//!
+//! fn main() {
+//!     let t = spawn square(4);
+//!     assert_eq(join t, 16);
+//! }
+//! ```
+//!
+//! Values are 64 bit signed integers. Functions get their arguments and `let` variables in their
+//! frame, module level `let` variables are copied into each spawned thread, and `global`
+//! variables are shared by every thread.
+//!
+//! `parallel_for(f, from, to)` and `parallel_reduce(map, combine, from, to)` take function names
+//! and run them over a range on a tree of forked threads.
+
+mod codegen;
+mod lexer;
+mod parser;
+mod peephole;
+
+use std::path::Path;
+
+use eyre::Context as _;
+
+use crate::{
+    asm::{ParseErrors, Source, Span},
+    Program,
+};
+
+/// An error and the source it's about.
+type Error = (Span, eyre::Report);
+
+impl Program {
+    /// Compiles a `.fl` program.
+    pub fn compile(s: &str) -> eyre::Result<Program> {
+        compile("<input>", s, true)
+    }
+
+    /// Compiles a `.fl` program without optimizing, to compare against what optimizing does.
+    pub fn compile_unoptimized(s: &str) -> eyre::Result<Program> {
+        compile("<input>", s, false)
+    }
+
+    /// Compiles the `.fl` program at `path`.
+    pub fn compile_file(path: &Path) -> eyre::Result<Program> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        compile(&path.display().to_string(), &contents, true)
+    }
+}
+
+fn compile(name: &str, s: &str, optimize: bool) -> eyre::Result<Program> {
+    let sources = vec![Source {
+        name: name.to_string(),
+        text: s.to_string(),
+    }];
+    let end = Span {
+        source: 0,
+        line: s.lines().count() + 1,
+        column: 1,
+        len: 0,
+    };
+
+    let output = lexer::tokenize(0, s)
+        .and_then(|tokens| parser::parse(&tokens, end))
+        .map_err(|e| vec![e])
+        .and_then(|module| codegen::generate(&module, end, optimize))
+        .map_err(|errors| ParseErrors::new("compile", &sources, errors))?;
+
+    let flasm = output.to_flasm();
+    let mut program =
+        Program::parse(&flasm).with_context(|| format!("Assembling compiled {name}:\n{flasm}"))?;
+
+    // Point traps at the `.fl` source rather than the generated flasm.
+    program.spans = output.spans();
+    program.sources = sources;
+    debug_assert_eq!(program.spans.len(), program.ops.len());
+    Ok(program)
+}
@@ -0,0 +1,778 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use super::{
+    parser::{Block, Expr, ExprKind, Function, Ident, Item, Module, Stmt},
+    peephole, Error,
+};
+use crate::{asm::Span, Word, WORD_SIZE};
+
+/// An operand of a generated op.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Arg {
+    Pop,
+    /// `$pop[n]`, removing the value `n` below the top of the stack.
+    PopAt(usize),
+    Peek,
+    Int(Word),
+    Label(String),
+    /// `$frame[offset]`
+    Frame(Word),
+    /// `$mem[:label]`
+    Mem(String),
+    ThreadId,
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arg::Pop => write!(f, "$pop"),
+            Arg::PopAt(n) => write!(f, "$pop[{n}]"),
+            Arg::Peek => write!(f, "$peek"),
+            Arg::Int(v) => write!(f, "{v}"),
+            Arg::Label(label) => write!(f, ":{label}"),
+            Arg::Frame(offset) => write!(f, "$frame[{offset}]"),
+            Arg::Mem(label) => write!(f, "$mem[:{label}]"),
+            Arg::ThreadId => write!(f, "$tid"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Inst {
+    Label(String),
+    Op {
+        name: &'static str,
+        args: Vec<Arg>,
+        /// The source that the op was generated for.
+        span: Span,
+    },
+}
+
+/// Compiled code, and the `.data` and `.gdata` sections for module variables.
+pub(super) struct Output {
+    pub(super) code: Vec<Inst>,
+    pub(super) data: Vec<String>,
+    pub(super) global_data: Vec<String>,
+}
+
+impl Output {
+    pub(super) fn to_flasm(&self) -> String {
+        let mut lines = Vec::new();
+        for inst in &self.code {
+            lines.push(match inst {
+                Inst::Label(label) => format!(":{label}"),
+                Inst::Op { name, args, .. } if args.is_empty() => name.to_string(),
+                Inst::Op { name, args, .. } => {
+                    let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                    format!("{name} {}", args.join(", "))
+                }
+            });
+        }
+        for (section, data) in [(".data", &self.data), (".gdata", &self.global_data)] {
+            if !data.is_empty() {
+                lines.push(section.to_string());
+                lines.extend(data.iter().cloned());
+            }
+        }
+        lines.join("\n") + "\n"
+    }
+
+    pub(super) fn spans(&self) -> Vec<Span> {
+        self.code
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Op { span, .. } => Some(*span),
+                Inst::Label(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Functions provided by the VM: name, arity and whether they evaluate to a value.
+const BUILTINS: &[(&str, usize, bool)] = &[
+    ("assert_eq", 2, false),
+    ("exit", 1, false),
+    ("debug", 0, false),
+    ("thread_id", 0, true),
+    ("parallel_for", 3, false),
+    ("parallel_reduce", 4, true),
+];
+
+/// Generates code for `module`. Unless `optimize` is false, values are used where they are
+/// instead of being pushed first, constants are folded and the code is cleaned up with
+/// [`peephole::optimize`].
+pub(super) fn generate(module: &Module, end: Span, optimize: bool) -> Result<Output, Vec<Error>> {
+    let mut codegen = Codegen {
+        optimize,
+        ..Default::default()
+    };
+
+    for item in &module.items {
+        match item {
+            Item::Function(f) => codegen.declare_function(f),
+            Item::Variable {
+                name,
+                value,
+                global,
+            } => codegen.declare_variable(name, value, *global),
+        }
+    }
+
+    match codegen.functions.get("main") {
+        Some(&(0, _)) => {}
+        Some(&(_, span)) => codegen.error(span, eyre::eyre!("fn main can't take arguments")),
+        None => codegen.error(end, eyre::eyre!("Missing fn main")),
+    }
+    codegen.op("CALL", vec![Arg::Label("fn_main".to_string())], end);
+    codegen.op("EXIT", vec![Arg::Pop], end);
+
+    for item in &module.items {
+        if let Item::Function(f) = item {
+            codegen.function(f);
+        }
+    }
+    for (name, span) in std::mem::take(&mut codegen.helpers) {
+        codegen.parallel_helper(name, span);
+    }
+
+    if !codegen.errors.is_empty() {
+        return Err(codegen.errors);
+    }
+    if optimize {
+        peephole::optimize(&mut codegen.output.code);
+    }
+    Ok(codegen.output)
+}
+
+struct Codegen<'s> {
+    optimize: bool,
+    output: Output,
+    errors: Vec<Error>,
+    /// Arity and where each function is declared.
+    functions: HashMap<&'s str, (usize, Span)>,
+    /// Labels of module variables.
+    variables: HashMap<&'s str, String>,
+    label_count: usize,
+    /// Helpers that were called, and where they were first called from.
+    helpers: BTreeMap<&'static str, Span>,
+
+    /// Frame offsets of the current function's variables, innermost block last.
+    scopes: Vec<HashMap<&'s str, Word>>,
+    frame_size: Word,
+    /// Where `continue` and `break` jump to, innermost loop last.
+    loops: Vec<(String, String)>,
+}
+
+impl Default for Codegen<'_> {
+    fn default() -> Self {
+        Codegen {
+            optimize: false,
+            output: Output {
+                code: Vec::new(),
+                data: Vec::new(),
+                global_data: Vec::new(),
+            },
+            errors: Vec::new(),
+            functions: HashMap::new(),
+            variables: HashMap::new(),
+            label_count: 0,
+            helpers: BTreeMap::new(),
+            scopes: Vec::new(),
+            frame_size: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+impl<'s> Codegen<'s> {
+    fn error(&mut self, span: Span, error: eyre::Report) {
+        self.errors.push((span, error));
+    }
+
+    fn op(&mut self, name: &'static str, args: Vec<Arg>, span: Span) {
+        self.output.code.push(Inst::Op { name, args, span });
+    }
+
+    fn new_label(&mut self) -> String {
+        self.label_count += 1;
+        format!("L{}", self.label_count)
+    }
+
+    fn label(&mut self, label: &str) {
+        self.output.code.push(Inst::Label(label.to_string()));
+    }
+
+    fn declare_function(&mut self, f: &Function<'s>) {
+        let name = f.name;
+        if BUILTINS.iter().any(|(b, _, _)| *b == name.name) {
+            self.error(name.span, eyre::eyre!("fn {} shadows a builtin", name.name));
+        }
+        let arity = f.params.len();
+        if self
+            .functions
+            .insert(name.name, (arity, name.span))
+            .is_some()
+        {
+            self.error(name.span, eyre::eyre!("Duplicate fn {}", name.name));
+        }
+    }
+
+    fn declare_variable(&mut self, name: &Ident<'s>, value: &Expr, global: bool) {
+        let Some(value) = constant(value) else {
+            let e = eyre::eyre!("Initial value of {} must be a constant", name.name);
+            return self.error(value.span, e);
+        };
+
+        let label = format!("var_{}", name.name);
+        let section = match global {
+            true => &mut self.output.global_data,
+            false => &mut self.output.data,
+        };
+        section.push(format!(":{label}"));
+        section.push(format!(".word {value}"));
+
+        if self.variables.insert(name.name, label).is_some() {
+            self.error(name.span, eyre::eyre!("Duplicate variable {}", name.name));
+        }
+    }
+
+    // Functions pop their arguments into their frame, and leave their return value on the stack.
+    fn function(&mut self, f: &Function<'s>) {
+        let span = f.name.span;
+        self.label(&format!("fn_{}", f.name.name));
+
+        let enter = self.output.code.len();
+        self.op("ENTER", vec![Arg::Int(0)], span);
+
+        self.scopes = vec![HashMap::new()];
+        self.frame_size = 0;
+        let offsets = f
+            .params
+            .iter()
+            .map(|param| self.declare_local(param))
+            .collect::<Vec<_>>();
+        for offset in offsets.into_iter().rev() {
+            self.op("STORE_FRAME", vec![Arg::Int(offset), Arg::Pop], span);
+        }
+
+        self.block(&f.body);
+        self.op("PUSH", vec![Arg::Int(0)], span);
+        self.op("RET", vec![], span);
+
+        self.output.code[enter] = Inst::Op {
+            name: "ENTER",
+            args: vec![Arg::Int(self.frame_size)],
+            span,
+        };
+    }
+
+    fn declare_local(&mut self, name: &Ident<'s>) -> Word {
+        let offset = self.frame_size;
+        self.frame_size += WORD_SIZE;
+        self.scopes
+            .last_mut()
+            .expect("inside a function")
+            .insert(name.name, offset);
+        offset
+    }
+
+    fn local(&self, name: &str) -> Option<Word> {
+        self.scopes.iter().rev().find_map(|s| s.get(name)).copied()
+    }
+
+    fn block(&mut self, block: &Block<'s>) {
+        self.scopes.push(HashMap::new());
+        for stmt in block {
+            self.stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt<'s>) {
+        match stmt {
+            Stmt::Let(name, value) => {
+                let value = self.value(value);
+                let offset = self.declare_local(name);
+                self.op("STORE_FRAME", vec![Arg::Int(offset), value], name.span);
+            }
+            Stmt::Assign(name, value) => {
+                let value = self.value(value);
+                if let Some(offset) = self.local(name.name) {
+                    self.op("STORE_FRAME", vec![Arg::Int(offset), value], name.span);
+                } else if let Some(label) = self.variables.get(name.name) {
+                    let args = vec![Arg::Label(label.clone()), value];
+                    self.op("STORE", args, name.span);
+                } else {
+                    self.error(name.span, eyre::eyre!("Unknown variable {}", name.name));
+                }
+            }
+
+            Stmt::If(condition, then, otherwise) => {
+                let else_label = self.new_label();
+                self.jump_unless(condition, &else_label);
+                self.block(then);
+
+                if otherwise.is_empty() {
+                    self.label(&else_label);
+                } else {
+                    let end = self.new_label();
+                    self.op("JUMP", vec![Arg::Label(end.clone())], condition.span);
+                    self.label(&else_label);
+                    self.block(otherwise);
+                    self.label(&end);
+                }
+            }
+            Stmt::While(condition, body) => {
+                let (top, end) = (self.new_label(), self.new_label());
+                self.label(&top);
+                self.jump_unless(condition, &end);
+
+                self.loops.push((top.clone(), end.clone()));
+                self.block(body);
+                self.loops.pop();
+
+                self.op("JUMP", vec![Arg::Label(top)], condition.span);
+                self.label(&end);
+            }
+            Stmt::Break(span) | Stmt::Continue(span) => {
+                let is_break = matches!(stmt, Stmt::Break(_));
+                match self.loops.last() {
+                    Some((top, end)) => {
+                        let target = if is_break { end } else { top };
+                        self.op("JUMP", vec![Arg::Label(target.clone())], *span);
+                    }
+                    None => {
+                        let keyword = if is_break { "break" } else { "continue" };
+                        self.error(*span, eyre::eyre!("{keyword} outside of a loop"));
+                    }
+                }
+            }
+            Stmt::Return(value, span) => {
+                match value {
+                    Some(value) => self.expr(value),
+                    None => self.op("PUSH", vec![Arg::Int(0)], *span),
+                }
+                self.op("RET", vec![], *span);
+            }
+
+            Stmt::Expr(expr) => {
+                let pushed = match &expr.kind {
+                    ExprKind::Call(f, args) => self.call(f, args),
+                    _ => self.value(expr) == Arg::Pop,
+                };
+                if pushed {
+                    self.op("NOP", vec![Arg::Pop], expr.span);
+                }
+            }
+        }
+    }
+
+    /// Jumps to `label` if `condition` is 0.
+    fn jump_unless(&mut self, condition: &Expr<'s>, label: &str) {
+        let target = Arg::Label(label.to_string());
+        if let (true, ExprKind::Binary(op, a, b)) = (self.optimize, &condition.kind) {
+            if *op == "&&" {
+                self.jump_unless(a, label);
+                return self.jump_unless(b, label);
+            }
+            let inverse = match *op {
+                "==" => Some("JUMP_NE"),
+                "!=" => Some("JUMP_EQ"),
+                "<" => Some("JUMP_IGE"),
+                "<=" => Some("JUMP_IGT"),
+                ">" => Some("JUMP_ILE"),
+                ">=" => Some("JUMP_ILT"),
+                _ => None,
+            };
+            if let Some(jump) = inverse {
+                let (a, b) = self.operands(a, b);
+                return self.op(jump, vec![a, b, target], condition.span);
+            }
+        }
+
+        let value = self.value(condition);
+        self.op("JUMP_EQ", vec![value, Arg::Int(0), target], condition.span);
+    }
+
+    /// Pushes the value of `expr`.
+    fn expr(&mut self, expr: &Expr<'s>) {
+        let value = self.value(expr);
+        if value != Arg::Pop {
+            self.op("PUSH", vec![value], expr.span);
+        }
+    }
+
+    /// Generates `expr`, returning an operand for its value. That's `$pop` for values computed
+    /// onto the stack, or when optimizing, constants and variables that can be read in place.
+    fn value(&mut self, expr: &Expr<'s>) -> Arg {
+        let span = expr.span;
+        if let Some(v) = constant(expr).filter(|_| self.optimize) {
+            return Arg::Int(v);
+        }
+
+        let direct = match &expr.kind {
+            ExprKind::Int(n) => match parse_int(n) {
+                Some(v) => Arg::Int(v),
+                None => {
+                    self.error(span, eyre::eyre!("Invalid integer {n}"));
+                    return Arg::Pop;
+                }
+            },
+            ExprKind::Var(name) => {
+                if let Some(offset) = self.local(name) {
+                    Arg::Frame(offset)
+                } else if let Some(label) = self.variables.get(name) {
+                    Arg::Mem(label.clone())
+                } else {
+                    self.error(span, eyre::eyre!("Unknown variable {name}"));
+                    return Arg::Pop;
+                }
+            }
+            ExprKind::Call(f, args) if f.name == "thread_id" && args.is_empty() => Arg::ThreadId,
+            _ => {
+                self.computed(expr);
+                return Arg::Pop;
+            }
+        };
+
+        if self.optimize {
+            return direct;
+        }
+        self.op("PUSH", vec![direct], span);
+        Arg::Pop
+    }
+
+    /// Generates `a` and `b`, returning operands that read them in order.
+    fn operands(&mut self, a: &Expr<'s>, b: &Expr<'s>) -> (Arg, Arg) {
+        let mut a_value = self.value(a);
+        // Module variables read in place would see changes made while evaluating `b`.
+        if matches!(a_value, Arg::Mem(_)) && !is_pure(b) {
+            self.op("PUSH", vec![a_value], a.span);
+            a_value = Arg::Pop;
+        }
+
+        match (a_value, self.value(b)) {
+            (Arg::Pop, Arg::Pop) => (Arg::PopAt(1), Arg::Pop),
+            (a, b) => (a, b),
+        }
+    }
+
+    /// Pushes the value of an expression that has to be computed.
+    fn computed(&mut self, expr: &Expr<'s>) {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Int(_) | ExprKind::Var(_) => unreachable!("Read directly"),
+
+            ExprKind::Unary(op, operand) => {
+                let value = self.value(operand);
+                match *op {
+                    "-" => self.op("INEG", vec![value], span),
+                    "!" => self.op("EQ", vec![value, Arg::Int(0)], span),
+                    "~" => self.op("NOT", vec![value], span),
+                    _ => unreachable!("Not a unary operator: {op}"),
+                }
+            }
+
+            // Short circuits, evaluating to 1 or 0.
+            ExprKind::Binary(op @ ("&&" | "||"), a, b) => {
+                let (short, end) = (self.new_label(), self.new_label());
+                let (jump, short_value) = match *op {
+                    "&&" => ("JUMP_EQ", 0),
+                    _ => ("JUMP_NE", 1),
+                };
+
+                let a = self.value(a);
+                let args = vec![a, Arg::Int(0), Arg::Label(short.clone())];
+                self.op(jump, args, span);
+                let b = self.value(b);
+                self.op("NE", vec![b, Arg::Int(0)], span);
+                self.op("JUMP", vec![Arg::Label(end.clone())], span);
+                self.label(&short);
+                self.op("PUSH", vec![Arg::Int(short_value)], span);
+                self.label(&end);
+            }
+            ExprKind::Binary(op, a, b) => {
+                let (a, b) = self.operands(a, b);
+                let name = match *op {
+                    "+" => "IADD",
+                    "-" => "ISUB",
+                    "*" => "IMUL",
+                    "/" => "IDIV",
+                    "%" => "IMOD",
+                    "&" => "AND",
+                    "|" => "OR",
+                    "^" => "XOR",
+                    "<<" => "SHIFT_LEFT",
+                    ">>" => "SHIFT_RIGHT_ARITH",
+                    "==" => "EQ",
+                    "!=" => "NE",
+                    "<" => "ILT",
+                    "<=" => "ILE",
+                    ">" => "IGT",
+                    ">=" => "IGE",
+                    _ => unreachable!("Not a binary operator: {op}"),
+                };
+                self.op(name, vec![a, b], span);
+            }
+
+            ExprKind::Call(f, args) => {
+                if !self.call(f, args) {
+                    let e = eyre::eyre!("{} doesn't return a value", f.name);
+                    self.error(f.span, e);
+                }
+            }
+            ExprKind::Spawn(f, args) => self.spawn(f, args, span),
+            ExprKind::Join(thread) => {
+                let thread = self.value(thread);
+                self.op("JOIN", vec![thread], span);
+            }
+        }
+    }
+
+    /// Calls `f`, returning whether it pushed a value.
+    fn call(&mut self, f: &Ident<'s>, args: &[Expr<'s>]) -> bool {
+        if let Some(&(_, arity, value)) = BUILTINS.iter().find(|(b, _, _)| *b == f.name) {
+            if args.len() != arity {
+                self.arity_error(f, arity, args.len());
+                return value;
+            }
+            match (f.name, args) {
+                ("assert_eq", [a, b]) => {
+                    let (a, b) = self.operands(a, b);
+                    self.op("ASSERT_EQ", vec![a, b], f.span);
+                }
+                ("exit", [code]) => {
+                    let code = self.value(code);
+                    self.op("EXIT", vec![code], f.span);
+                }
+                ("debug", []) => self.op("DEBUG", vec![], f.span),
+                ("thread_id", []) => self.op("PUSH", vec![Arg::ThreadId], f.span),
+                ("parallel_for", [each, from, to]) => {
+                    self.function_address(each, 1);
+                    self.expr(from);
+                    self.expr(to);
+                    self.call_helper("parallel_for", f.span);
+                    self.op("NOP", vec![Arg::Pop], f.span);
+                }
+                ("parallel_reduce", [map, combine, from, to]) => {
+                    self.function_address(map, 1);
+                    self.function_address(combine, 2);
+                    self.expr(from);
+                    self.expr(to);
+                    self.call_helper("parallel_reduce", f.span);
+                }
+                _ => unreachable!("Unhandled builtin: {}", f.name),
+            }
+            return value;
+        }
+
+        for arg in args {
+            self.expr(arg);
+        }
+        self.check_arity(f, args.len());
+        let target = Arg::Label(format!("fn_{}", f.name));
+        self.op("CALL", vec![target], f.span);
+        true
+    }
+
+    // The child thread starts with the parent's stack plus the parent's id, and the parent
+    // continues with the child's id on top.
+    fn spawn(&mut self, f: &Ident<'s>, args: &[Expr<'s>], span: Span) {
+        for arg in args {
+            self.expr(arg);
+        }
+        self.check_arity(f, args.len());
+
+        let (child, end) = (self.new_label(), self.new_label());
+        self.op("FORK", vec![Arg::Label(child.clone())], span);
+        for _ in args {
+            self.op("NOP", vec![Arg::PopAt(1)], span);
+        }
+        self.op("JUMP", vec![Arg::Label(end.clone())], span);
+
+        self.label(&child);
+        self.op("NOP", vec![Arg::Pop], span);
+        let target = Arg::Label(format!("fn_{}", f.name));
+        self.op("CALL", vec![target], span);
+        self.op("THREAD_FINISH", vec![Arg::Pop], span);
+        self.label(&end);
+    }
+
+    /// Pushes the address of the function named by `expr`, which will be called with `arity`
+    /// arguments.
+    fn function_address(&mut self, expr: &Expr<'s>, arity: usize) {
+        let name = match expr.kind {
+            ExprKind::Var(name) if self.local(name).is_none() => name,
+            _ => return self.error(expr.span, eyre::eyre!("Expected a function name")),
+        };
+        let f = Ident {
+            name,
+            span: expr.span,
+        };
+        self.check_arity(&f, arity);
+        let address = Arg::Label(format!("fn_{name}"));
+        self.op("PUSH", vec![address], expr.span);
+    }
+
+    fn call_helper(&mut self, name: &'static str, span: Span) {
+        self.helpers.entry(name).or_insert(span);
+        self.op("CALL", vec![Arg::Label(name.to_string())], span);
+    }
+
+    // `parallel_for(each, from, to)` and `parallel_reduce(map, combine, from, to)` split the
+    // range in half, spawning a thread for the lower half and recursing on the upper half, until
+    // ranges have a single value to call `each` or `map` with. `parallel_reduce` then combines
+    // the halves as their threads are joined. Empty ranges evaluate to 0.
+    fn parallel_helper(&mut self, name: &'static str, span: Span) {
+        let functions = match name {
+            "parallel_for" => 1,
+            "parallel_reduce" => 2,
+            _ => unreachable!("Not a helper: {name}"),
+        };
+        let (from, to, mid) = (
+            functions * WORD_SIZE,
+            (functions + 1) * WORD_SIZE,
+            (functions + 2) * WORD_SIZE,
+        );
+        let (split, lower, empty) = (
+            format!("{name}_split"),
+            format!("{name}_lower"),
+            format!("{name}_empty"),
+        );
+        let frame = Arg::Frame;
+
+        self.label(name);
+        self.op("ENTER", vec![Arg::Int(mid + WORD_SIZE)], span);
+        for slot in (0..functions + 2).rev() {
+            self.op(
+                "STORE_FRAME",
+                vec![Arg::Int(slot * WORD_SIZE), Arg::Pop],
+                span,
+            );
+        }
+
+        self.op("ISUB", vec![frame(to), frame(from)], span);
+        self.op(
+            "JUMP_IGT",
+            vec![Arg::Pop, Arg::Int(1), Arg::Label(split.clone())],
+            span,
+        );
+        self.op(
+            "JUMP_ILE",
+            vec![frame(to), frame(from), Arg::Label(empty.clone())],
+            span,
+        );
+        self.op("PUSH", vec![frame(from)], span);
+        self.op("CALL", vec![frame(0)], span);
+        self.op("RET", vec![], span);
+        self.label(&empty);
+        self.op("PUSH", vec![Arg::Int(0)], span);
+        self.op("RET", vec![], span);
+
+        self.label(&split);
+        self.op("ISUB", vec![frame(to), frame(from)], span);
+        self.op("IDIV", vec![Arg::Pop, Arg::Int(2)], span);
+        self.op("IADD", vec![frame(from), Arg::Pop], span);
+        self.op("STORE_FRAME", vec![Arg::Int(mid), Arg::Pop], span);
+        self.op("FORK", vec![Arg::Label(lower.clone())], span);
+        let recurse = |codegen: &mut Self, range_from, range_to| {
+            for offset in (0..functions).map(|f| f * WORD_SIZE) {
+                codegen.op("PUSH", vec![frame(offset)], span);
+            }
+            codegen.op("PUSH", vec![frame(range_from)], span);
+            codegen.op("PUSH", vec![frame(range_to)], span);
+            codegen.op("CALL", vec![Arg::Label(name.to_string())], span);
+        };
+
+        // The upper half, leaving the lower half's thread id below its result.
+        recurse(self, mid, to);
+        self.op("JOIN", vec![Arg::PopAt(1)], span);
+        if functions == 2 {
+            // Lower half first.
+            self.op("PUSH", vec![Arg::PopAt(1)], span);
+            self.op("CALL", vec![frame(WORD_SIZE)], span);
+        } else {
+            self.op("NOP", vec![Arg::Pop], span);
+        }
+        self.op("RET", vec![], span);
+
+        self.label(&lower);
+        self.op("NOP", vec![Arg::Pop], span);
+        recurse(self, from, mid);
+        self.op("THREAD_FINISH", vec![Arg::Pop], span);
+    }
+
+    fn check_arity(&mut self, f: &Ident<'s>, got: usize) {
+        match self.functions.get(f.name) {
+            Some(&(arity, _)) if arity != got => self.arity_error(f, arity, got),
+            Some(_) => {}
+            None => self.error(f.span, eyre::eyre!("Unknown fn {}", f.name)),
+        }
+    }
+
+    fn arity_error(&mut self, f: &Ident<'s>, expected: usize, got: usize) {
+        let e = eyre::eyre!("{} expects {expected} arguments, got {got}", f.name);
+        self.error(f.span, e);
+    }
+}
+
+fn parse_int(s: &str) -> Option<Word> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Word::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Whether evaluating `expr` can't change any variables.
+fn is_pure(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Int(_) | ExprKind::Var(_) => true,
+        ExprKind::Unary(_, operand) => is_pure(operand),
+        ExprKind::Binary(_, a, b) => is_pure(a) && is_pure(b),
+        ExprKind::Call(f, args) => f.name == "thread_id" && args.is_empty(),
+        ExprKind::Spawn(..) | ExprKind::Join(_) => false,
+    }
+}
+
+/// The value of an expression made only of literals. Expressions that would trap, like
+/// overflows, aren't constant so that they still trap when run.
+fn constant(expr: &Expr) -> Option<Word> {
+    let signed = |e: &Expr| constant(e).map(|v| v as i64);
+    let v = match &expr.kind {
+        ExprKind::Int(n) => return parse_int(n),
+        // Overflow isn't folded, so it traps at runtime whether or not the code is optimized.
+        ExprKind::Unary("-", operand) => signed(operand)?.checked_neg()?,
+        ExprKind::Unary("!", operand) => (signed(operand)? == 0) as i64,
+        ExprKind::Unary("~", operand) => !signed(operand)?,
+        ExprKind::Binary(op, a, b) => {
+            let (a, b) = (signed(a)?, signed(b)?);
+            let shift = || u32::try_from(b).ok().filter(|&b| b < i64::BITS);
+            match *op {
+                "+" => a.checked_add(b)?,
+                "-" => a.checked_sub(b)?,
+                "*" => a.checked_mul(b)?,
+                "/" => a.checked_div(b)?,
+                "%" => a.checked_rem(b)?,
+                "&" => a & b,
+                "|" => a | b,
+                "^" => a ^ b,
+                "<<" => ((a as Word) << shift()?) as i64,
+                ">>" => a >> shift()?,
+                "==" => (a == b) as i64,
+                "!=" => (a != b) as i64,
+                "<" => (a < b) as i64,
+                "<=" => (a <= b) as i64,
+                ">" => (a > b) as i64,
+                ">=" => (a >= b) as i64,
+                "&&" => (a != 0 && b != 0) as i64,
+                "||" => (a != 0 || b != 0) as i64,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(v as Word)
+}
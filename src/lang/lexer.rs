@@ -0,0 +1,77 @@
+use super::Error;
+use crate::asm::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TokenKind {
+    Int,
+    Ident,
+    Keyword,
+    Punct,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Token<'s> {
+    pub(super) kind: TokenKind,
+    pub(super) text: &'s str,
+    pub(super) span: Span,
+}
+
+const KEYWORDS: &[&str] = &[
+    "fn", "let", "global", "if", "else", "while", "break", "continue", "return", "spawn", "join",
+];
+
+/// Longest first, so `<<` isn't lexed as two `<`.
+const PUNCTS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+    "!", "<", ">", "=", "(", ")", "{", "}", ",", ";",
+];
+
+pub(super) fn tokenize(source: usize, s: &str) -> Result<Vec<Token<'_>>, Error> {
+    let mut tokens = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let span = |start: usize, len: usize| Span {
+            source,
+            line: i + 1,
+            column: start + 1,
+            len,
+        };
+
+        let mut pos = 0;
+        while let Some(c) = line[pos..].chars().next() {
+            let rest = &line[pos..];
+            if c.is_whitespace() {
+                pos += c.len_utf8();
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+
+            let word_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let (kind, len) = if c.is_ascii_digit() {
+                (TokenKind::Int, word_len)
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                match KEYWORDS.contains(&&rest[..word_len]) {
+                    true => (TokenKind::Keyword, word_len),
+                    false => (TokenKind::Ident, word_len),
+                }
+            } else {
+                let punct = PUNCTS
+                    .iter()
+                    .find(|p| rest.starts_with(*p))
+                    .ok_or_else(|| (span(pos, c.len_utf8()), eyre::eyre!("Unexpected {c:?}")))?;
+                (TokenKind::Punct, punct.len())
+            };
+
+            tokens.push(Token {
+                kind,
+                text: &rest[..len],
+                span: span(pos, len),
+            });
+            pos += len;
+        }
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,366 @@
+use super::{
+    lexer::{Token, TokenKind},
+    Error,
+};
+use crate::asm::Span;
+
+#[derive(Debug)]
+pub(super) struct Module<'s> {
+    pub(super) items: Vec<Item<'s>>,
+}
+
+#[derive(Debug)]
+pub(super) enum Item<'s> {
+    /// `global NAME = VALUE;` is shared by every thread, `let NAME = VALUE;` is copied into each
+    /// thread when it's spawned.
+    Variable {
+        name: Ident<'s>,
+        value: Expr<'s>,
+        global: bool,
+    },
+    Function(Function<'s>),
+}
+
+#[derive(Debug)]
+pub(super) struct Function<'s> {
+    pub(super) name: Ident<'s>,
+    pub(super) params: Vec<Ident<'s>>,
+    pub(super) body: Block<'s>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Ident<'s> {
+    pub(super) name: &'s str,
+    pub(super) span: Span,
+}
+
+pub(super) type Block<'s> = Vec<Stmt<'s>>;
+
+#[derive(Debug)]
+pub(super) enum Stmt<'s> {
+    Let(Ident<'s>, Expr<'s>),
+    Assign(Ident<'s>, Expr<'s>),
+    If(Expr<'s>, Block<'s>, Block<'s>),
+    While(Expr<'s>, Block<'s>),
+    Break(Span),
+    Continue(Span),
+    Return(Option<Expr<'s>>, Span),
+    Expr(Expr<'s>),
+}
+
+#[derive(Debug)]
+pub(super) struct Expr<'s> {
+    pub(super) kind: ExprKind<'s>,
+    pub(super) span: Span,
+}
+
+#[derive(Debug)]
+pub(super) enum ExprKind<'s> {
+    Int(&'s str),
+    Var(&'s str),
+    Unary(&'s str, Box<Expr<'s>>),
+    Binary(&'s str, Box<Expr<'s>>, Box<Expr<'s>>),
+    Call(Ident<'s>, Vec<Expr<'s>>),
+    /// Calls the function on a new thread, evaluating to the thread's id.
+    Spawn(Ident<'s>, Vec<Expr<'s>>),
+    /// Waits for a spawned thread, evaluating to what its function returned.
+    Join(Box<Expr<'s>>),
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    }
+}
+
+pub(super) fn parse<'s>(tokens: &[Token<'s>], end: Span) -> Result<Module<'s>, Error> {
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end,
+    };
+
+    let mut items = Vec::new();
+    while parser.peek().is_some() {
+        items.push(parser.item()?);
+    }
+    Ok(Module { items })
+}
+
+struct Parser<'t, 's> {
+    tokens: &'t [Token<'s>],
+    pos: usize,
+    /// Where errors about running out of tokens point.
+    end: Span,
+}
+
+impl<'s> Parser<'_, 's> {
+    fn peek(&self) -> Option<Token<'s>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.peek()
+            .is_some_and(|t| t.text == text && t.kind != TokenKind::Ident)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token<'s>, Error> {
+        let token = self.peek().ok_or_else(|| {
+            (
+                self.end,
+                eyre::eyre!("Expected {expected}, found end of file"),
+            )
+        })?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        let found = self.peek_is(text);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Span, Error> {
+        let token = self.next(&format!("{text:?}"))?;
+        if token.text != text || token.kind == TokenKind::Ident {
+            return Err(unexpected(token, &format!("{text:?}")));
+        }
+        Ok(token.span)
+    }
+
+    fn ident(&mut self) -> Result<Ident<'s>, Error> {
+        let token = self.next("a name")?;
+        if token.kind != TokenKind::Ident {
+            return Err(unexpected(token, "a name"));
+        }
+        Ok(Ident {
+            name: token.text,
+            span: token.span,
+        })
+    }
+
+    fn item(&mut self) -> Result<Item<'s>, Error> {
+        let token = self.next("fn, let or global")?;
+        match token.text {
+            "fn" if token.kind == TokenKind::Keyword => {
+                let name = self.ident()?;
+                self.expect("(")?;
+                let params = self.list(")", Self::ident)?;
+                let body = self.block()?;
+                Ok(Item::Function(Function { name, params, body }))
+            }
+            "let" | "global" if token.kind == TokenKind::Keyword => {
+                let name = self.ident()?;
+                self.expect("=")?;
+                let value = self.expr(0)?;
+                self.expect(";")?;
+                Ok(Item::Variable {
+                    name,
+                    value,
+                    global: token.text == "global",
+                })
+            }
+            _ => Err(unexpected(token, "fn, let or global")),
+        }
+    }
+
+    /// Items separated by commas, up to and including `close`.
+    fn list<T>(
+        &mut self,
+        close: &str,
+        mut item: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = Vec::new();
+        while !self.eat(close) {
+            items.push(item(self)?);
+            if !self.eat(",") {
+                self.expect(close)?;
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    fn block(&mut self) -> Result<Block<'s>, Error> {
+        self.expect("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat("}") {
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt<'s>, Error> {
+        let token = self.peek().ok_or_else(|| {
+            (
+                self.end,
+                eyre::eyre!("Expected a statement, found end of file"),
+            )
+        })?;
+
+        let stmt = match (token.kind, token.text) {
+            (TokenKind::Keyword, "let") => {
+                self.pos += 1;
+                let name = self.ident()?;
+                self.expect("=")?;
+                Stmt::Let(name, self.expr(0)?)
+            }
+            (TokenKind::Ident, _)
+                if self.tokens.get(self.pos + 1).is_some_and(|t| t.text == "=") =>
+            {
+                let name = self.ident()?;
+                self.expect("=")?;
+                Stmt::Assign(name, self.expr(0)?)
+            }
+            (TokenKind::Keyword, "if") => return self.if_stmt(),
+            (TokenKind::Keyword, "while") => {
+                self.pos += 1;
+                let condition = self.expr(0)?;
+                return Ok(Stmt::While(condition, self.block()?));
+            }
+            (TokenKind::Keyword, "break") => {
+                self.pos += 1;
+                Stmt::Break(token.span)
+            }
+            (TokenKind::Keyword, "continue") => {
+                self.pos += 1;
+                Stmt::Continue(token.span)
+            }
+            (TokenKind::Keyword, "return") => {
+                self.pos += 1;
+                let value = match self.peek_is(";") {
+                    true => None,
+                    false => Some(self.expr(0)?),
+                };
+                Stmt::Return(value, token.span)
+            }
+            _ => Stmt::Expr(self.expr(0)?),
+        };
+        self.expect(";")?;
+        Ok(stmt)
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt<'s>, Error> {
+        self.expect("if")?;
+        let condition = self.expr(0)?;
+        let then = self.block()?;
+        let otherwise = match (self.eat("else"), self.peek_is("if")) {
+            (false, _) => Vec::new(),
+            (true, true) => vec![self.if_stmt()?],
+            (true, false) => self.block()?,
+        };
+        Ok(Stmt::If(condition, then, otherwise))
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr<'s>, Error> {
+        let mut lhs = self.unary()?;
+
+        while let Some(token) = self.peek() {
+            let Some(precedence) =
+                precedence(token.text).filter(|_| token.kind == TokenKind::Punct)
+            else {
+                break;
+            };
+            if precedence < min_precedence {
+                break;
+            }
+
+            self.pos += 1;
+            let rhs = self.expr(precedence + 1)?;
+            lhs = Expr {
+                span: join(lhs.span, rhs.span),
+                kind: ExprKind::Binary(token.text, Box::new(lhs), Box::new(rhs)),
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr<'s>, Error> {
+        let token = self.next("an expression")?;
+        let kind = match (token.kind, token.text) {
+            (TokenKind::Punct, op @ ("-" | "!" | "~")) => {
+                let operand = self.unary()?;
+                return Ok(Expr {
+                    span: join(token.span, operand.span),
+                    kind: ExprKind::Unary(op, Box::new(operand)),
+                });
+            }
+            (TokenKind::Punct, "(") => {
+                let inner = self.expr(0)?;
+                let close = self.expect(")")?;
+                return Ok(Expr {
+                    span: join(token.span, close),
+                    kind: inner.kind,
+                });
+            }
+
+            (TokenKind::Int, n) => ExprKind::Int(n),
+            (TokenKind::Ident, name) if self.peek_is("(") => {
+                self.pos += 1;
+                let args = self.list(")", |p| p.expr(0))?;
+                ExprKind::Call(
+                    Ident {
+                        name,
+                        span: token.span,
+                    },
+                    args,
+                )
+            }
+            (TokenKind::Ident, name) => ExprKind::Var(name),
+
+            (TokenKind::Keyword, "spawn") => {
+                let function = self.ident()?;
+                self.expect("(")?;
+                let args = self.list(")", |p| p.expr(0))?;
+                ExprKind::Spawn(function, args)
+            }
+            (TokenKind::Keyword, "join") => {
+                let thread = self.unary()?;
+                return Ok(Expr {
+                    span: join(token.span, thread.span),
+                    kind: ExprKind::Join(Box::new(thread)),
+                });
+            }
+
+            _ => return Err(unexpected(token, "an expression")),
+        };
+
+        let end = self.tokens[self.pos - 1].span;
+        Ok(Expr {
+            kind,
+            span: join(token.span, end),
+        })
+    }
+}
+
+fn unexpected(token: Token, expected: &str) -> Error {
+    (
+        token.span,
+        eyre::eyre!("Expected {expected}, found {:?}", token.text),
+    )
+}
+
+/// Span from the start of `a` to the end of `b`, or just `a` if they're on different lines.
+fn join(a: Span, b: Span) -> Span {
+    if a.line != b.line || b.column < a.column {
+        return a;
+    }
+    Span {
+        len: b.column + b.len - a.column,
+        ..a
+    }
+}
@@ -0,0 +1,126 @@
+//! Cleans up generated code by rewriting short runs of ops, until nothing more changes.
+
+use super::codegen::{Arg, Inst};
+
+pub(super) fn optimize(code: &mut Vec<Inst>) {
+    while remove_unreachable(code) | rewrite_pairs(code) {}
+}
+
+/// Removes ops after an unconditional jump, up to the next label.
+fn remove_unreachable(code: &mut Vec<Inst>) -> bool {
+    let before = code.len();
+    let mut reachable = true;
+    code.retain(|inst| match inst {
+        Inst::Label(_) => {
+            reachable = true;
+            true
+        }
+        Inst::Op { name, .. } => {
+            let keep = reachable;
+            if matches!(*name, "JUMP" | "RET" | "EXIT" | "THREAD_FINISH") {
+                reachable = false;
+            }
+            keep
+        }
+    });
+    code.len() != before
+}
+
+fn rewrite_pairs(code: &mut Vec<Inst>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        let next = code.get(i + 1);
+        match (&code[i], next) {
+            // Jumping to the label that follows anyway.
+            (
+                Inst::Op {
+                    name: "JUMP", args, ..
+                },
+                _,
+            ) if falls_through_to(&code[i + 1..], args) => {
+                code.remove(i);
+                changed = true;
+            }
+
+            // Storing a value then reading it back, instead keeping it on the stack for the read.
+            (
+                Inst::Op {
+                    name: "STORE_FRAME",
+                    args: stored,
+                    ..
+                },
+                Some(Inst::Op { args, .. }),
+            ) if stored[1] == Arg::Pop && reads_back(&stored[0], args).is_some() => {
+                let read = reads_back(&stored[0], args).expect("checked by guard");
+                if let Inst::Op { args, .. } = &mut code[i] {
+                    args[1] = Arg::Peek;
+                }
+                match &mut code[i + 1] {
+                    Inst::Op { name: "PUSH", .. } => {
+                        code.remove(i + 1);
+                    }
+                    Inst::Op { args, .. } => args[read] = Arg::Pop,
+                    Inst::Label(_) => unreachable!("matched an op"),
+                }
+                changed = true;
+            }
+
+            // Pushing a value only to discard it.
+            (
+                Inst::Op {
+                    name: "PUSH",
+                    args: pushed,
+                    ..
+                },
+                Some(Inst::Op {
+                    name: "NOP",
+                    args: popped,
+                    ..
+                }),
+            ) if !reads_stack(&pushed[0]) && popped[..] == [Arg::Pop] => {
+                code.drain(i..i + 2);
+                changed = true;
+            }
+
+            _ => i += 1,
+        }
+    }
+    changed
+}
+
+/// Whether the labels at the start of `code` include the target of a jump with `args`.
+fn falls_through_to(code: &[Inst], args: &[Arg]) -> bool {
+    let [Arg::Label(target)] = args else {
+        return false;
+    };
+    code.iter()
+        .map_while(|inst| match inst {
+            Inst::Label(label) => Some(label),
+            Inst::Op { .. } => None,
+        })
+        .any(|label| label == target)
+}
+
+/// Which of `args` reads the frame slot at `offset`, if that's the only one and none of the
+/// others read the stack, so that it could read the stack instead.
+fn reads_back(offset: &Arg, args: &[Arg]) -> Option<usize> {
+    let &Arg::Int(offset) = offset else {
+        return None;
+    };
+    if args.iter().any(reads_stack) {
+        return None;
+    }
+    let mut reads = args
+        .iter()
+        .enumerate()
+        .filter(|(_, arg)| **arg == Arg::Frame(offset));
+    match (reads.next(), reads.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None,
+    }
+}
+
+fn reads_stack(arg: &Arg) -> bool {
+    matches!(arg, Arg::Pop | Arg::PopAt(_) | Arg::Peek)
+}
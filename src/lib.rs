@@ -1,22 +1,34 @@
+mod asm;
+mod bytecode;
+mod channel;
 mod event;
+mod lang;
+mod memory;
 pub mod rand;
 mod remote;
 mod spawner;
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::BTreeMap,
     ops::Deref,
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
+pub use asm::format_flasm;
+use asm::{Source, Span};
+use channel::Channels;
 use event::EventListener;
 use eyre::{Context as _, OptionExt};
+use memory::PagedMemory;
 use rand::Rand;
 use remote::Peers;
 use spawner::Spawner;
 use tokio::{
-    sync::{broadcast, Mutex, RwLock},
+    sync::{broadcast, watch, Mutex},
     task::JoinSet,
 };
 
@@ -25,7 +37,17 @@ const WORD_SIZE: Word = core::mem::size_of::<Word>() as Word;
 
 pub type Stack = Vec<Word>;
 
-type Memory = BTreeMap<Word, Word>;
+/// Address where `.data` is loaded in thread-local memory, and `.gdata` in global memory.
+const DATA_BASE: Word = 1 << (WORD_SIZE * 8 - 3);
+/// Local address where the frames of CALLed functions start.
+const FRAME_BASE: Word = 1 << (WORD_SIZE * 8 - 2);
+const MAX_CALL_DEPTH: usize = 1 << 16;
+/// Deepest an operand can nest, as in `$mem[$mem[...]]`.
+const MAX_OPERAND_DEPTH: usize = 64;
+/// Words from the top of the stack shown when a thread traps.
+const TRAP_STACK_WORDS: usize = 8;
+/// Ops a thread runs between giving other threads a turn.
+const YIELD_STEPS: u64 = 128;
 
 // What goes in Eal?
 //   - Network
@@ -48,24 +70,67 @@ impl Eal for RealEal {
 pub struct HostCtx {
     #[allow(unused)]
     eal: Box<dyn Eal>,
+    rand: Rand,
+    process_count: AtomicU64,
     spawner: Spawner,
+    channels: Channels,
     eprint: Mutex<()>,
     peers: Arc<Peers>,
 }
 
 impl HostCtx {
     pub async fn execute(self: &Arc<Self>, program: Program) -> eyre::Result<Word> {
+        Ok(self.execute_counted(program).await?.0)
+    }
+
+    /// Like [`HostCtx::execute`], also counting what the process's threads did. Threads that
+    /// are still running when the process ends may not be counted.
+    pub async fn execute_counted(
+        self: &Arc<Self>,
+        program: Program,
+    ) -> eyre::Result<(Word, Counters)> {
+        let mut root = ThreadState::new();
+        for (&addr, &v) in &program.data {
+            root.write_memory(addr, v)?;
+        }
+
+        let count = self.process_count.fetch_add(1, Ordering::Relaxed);
         let process_ctx = Arc::new(ProcessCtx {
+            id: self.rand.get("process_id").get(count.to_string()).word(),
             host: Arc::clone(self),
+            global_memory: std::sync::RwLock::new(PagedMemory::from_words(
+                program
+                    .global_data
+                    .iter()
+                    .map(|(&addr, &v)| (addr / WORD_SIZE, v)),
+            )),
             program,
-            global_memory: Default::default(),
+            outcome: Default::default(),
+            ended: watch::Sender::new(false),
+            counters: Default::default(),
         });
 
-        let root_id = process_ctx.spawn(ThreadState::new()).await?;
-        match process_ctx.join(root_id).await? {
-            ThreadResult::Exit(code) => Ok(code),
-            ThreadResult::Finish(value) => Ok(value),
+        // The process ends when the root thread does, or earlier if another thread EXITs.
+        let root_id = process_ctx.spawn(root).await?;
+        let mut ended = process_ctx.ended.subscribe();
+        tokio::select! {
+            result = process_ctx.join(root_id) => {
+                let code = result.and_then(|r| r.code().ok_or_eyre("The root thread was killed"));
+                process_ctx.end(code);
+            }
+            _ = ended.wait_for(|&ended| ended) => {}
         }
+
+        self.spawner.forget(&process_ctx).await;
+        self.channels.forget(&process_ctx).await;
+        let outcome = process_ctx
+            .outcome
+            .lock()
+            .expect("outcome lock poisoned")
+            .take();
+        let code = outcome.expect("outcome is set when the process ends")?;
+        let counters = *process_ctx.counters.lock().expect("counters lock poisoned");
+        Ok((code, counters))
     }
 
     fn spawn_tasks(self: &Arc<Self>) {
@@ -89,18 +154,44 @@ impl HostCtx {
 }
 
 struct ProcessCtx {
+    /// Names the process in messages to peers.
+    id: Word,
     host: Arc<HostCtx>,
     program: Program,
-    global_memory: RwLock<Memory>,
+    global_memory: std::sync::RwLock<PagedMemory>,
+    /// How the process ended, set by whichever thread ended it first.
+    outcome: std::sync::Mutex<Option<eyre::Result<Word>>>,
+    /// Becomes true when the process ends, stopping all of its threads.
+    ended: watch::Sender<bool>,
+    /// Totals of the threads that have ended.
+    counters: std::sync::Mutex<Counters>,
+}
+
+/// What the threads of a process did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// Ops executed.
+    pub ops: u64,
+    /// Words read from or written to memory, not counting the stack.
+    pub memory_accesses: u64,
 }
 
 impl ProcessCtx {
+    /// Ends the process with `outcome`, unless it already ended.
+    fn end(&self, outcome: eyre::Result<Word>) {
+        let mut current = self.outcome.lock().expect("outcome lock poisoned");
+        if current.is_none() {
+            *current = Some(outcome);
+            self.ended.send_replace(true);
+        }
+    }
+
     async fn spawn(self: &Arc<Self>, state: ThreadState) -> eyre::Result<Word> {
-        Ok(self.spawner.spawn(self, state).await?)
+        self.spawner.spawn(self, state).await
     }
 
     async fn join(&self, tid: Word) -> eyre::Result<ThreadResult> {
-        Ok(self.spawner.join(tid).await?)
+        self.spawner.join(tid).await
     }
 }
 
@@ -116,27 +207,92 @@ struct ThreadCtx {
     proc: Arc<ProcessCtx>,
     id: Word,
     state: ThreadState,
+    /// Becomes true when another thread KILLs this one.
+    killed: watch::Receiver<bool>,
+    /// Added to the process's counters when the thread ends.
+    counters: Counters,
 }
 
 impl ThreadCtx {
     async fn execute(mut self) -> eyre::Result<ThreadResult> {
+        let mut ended = self.proc.ended.subscribe();
+        let mut killed = self.killed.clone();
+        let run = self.run(ended.clone());
+        // Also stops threads that are waiting, in a JOIN or CHAN_RECV.
+        let result = tokio::select! {
+            biased;
+            Ok(_) = ended.wait_for(|&ended| ended) => Err(eyre::eyre!("Cancelled, the process ended")),
+            Ok(_) = killed.wait_for(|&killed| killed) => Ok(ThreadResult::Killed),
+            result = run => result,
+        };
+
+        let mut counters = self.proc.counters.lock().expect("counters lock poisoned");
+        counters.ops += self.counters.ops;
+        counters.memory_accesses += self.counters.memory_accesses;
+        result
+    }
+
+    async fn run(&mut self, ended: watch::Receiver<bool>) -> eyre::Result<ThreadResult> {
         // TODO(shelbyd): Do we have to clone?
         let proc = Arc::clone(&self.proc);
         let ops = &proc.program.ops;
 
+        let mut steps: u64 = 0;
         loop {
+            // Yields now and then, so threads that never wait can't starve the others.
+            steps = steps.wrapping_add(1);
+            if steps.is_multiple_of(YIELD_STEPS) {
+                tokio::task::yield_now().await;
+            }
+            // Cancellation points. These only ever change to true.
+            if ended.has_changed().unwrap_or(false) {
+                eyre::bail!("Cancelled, the process ended");
+            }
+            if self.killed.has_changed().unwrap_or(false) {
+                return Ok(ThreadResult::Killed);
+            }
+
             let Some(op) = ops.get(self.state.instruction_pointer as usize) else {
                 return Ok(ThreadResult::Exit(0));
             };
 
+            let ip = self.state.instruction_pointer;
             self.state.instruction_pointer += 1;
-
-            if let Some(r) = op.execute(&mut self).await? {
+            self.counters.ops += 1;
+
+            // Ops pop their operands, so a trap shows the stack from before the op.
+            let stack = StackTop::of(&self.state.stack);
+            let result = op
+                .execute(self)
+                .await
+                .with_context(|| self.trap_context(ip, &stack))?;
+            if let Some(r) = result {
                 return Ok(r);
             }
         }
     }
 
+    /// Describes where this thread trapped: the op's source and the top of the stack before it.
+    fn trap_context(&self, ip: Word, stack: &StackTop) -> String {
+        let program = &self.proc.program;
+        // Programs loaded from bytecode have no spans.
+        let snippet = program
+            .spans
+            .get(ip as usize)
+            .map(|span| span.snippet(&program.sources))
+            .unwrap_or_default();
+
+        let top = stack.words();
+        let elided = if top.len() < stack.len { "..., " } else { "" };
+        let top = top.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+        format!(
+            "Thread {} trapped at instruction {ip}\n{snippet}stack: [{elided}{}]",
+            self.id,
+            top.join(", "),
+        )
+    }
+
     async fn get(&mut self, val_sp: &ValSp) -> eyre::Result<Word> {
         match val_sp {
             ValSp::Literal(v) => Ok(*v),
@@ -144,8 +300,14 @@ impl ThreadCtx {
             ValSp::Pop => self.state.stack.pop().ok_or_eyre("Pop from empty stack"),
             ValSp::PopI(i) => {
                 let i = Box::pin(self.get(i)).await? as usize;
-                let index = self.state.stack.len() - i - 1;
-                Ok(self.state.stack.remove(index as usize))
+                let index = self
+                    .state
+                    .stack
+                    .len()
+                    .checked_sub(i)
+                    .and_then(|n| n.checked_sub(1))
+                    .ok_or_eyre(format!("Pop index {i} past bottom of stack"))?;
+                Ok(self.state.stack.remove(index))
             }
 
             ValSp::Peek => self
@@ -164,22 +326,33 @@ impl ThreadCtx {
                 Ok(self.read_memory(to_global(addr)).await?)
             }
 
+            ValSp::Frame(offset) => {
+                let offset = Box::pin(self.get(offset)).await?;
+                self.read_frame(offset)
+            }
+            ValSp::FramePointer => Ok(self.state.frame.base),
+
             ValSp::ThreadId => Ok(self.id),
         }
     }
 
-    async fn read_memory(&self, addr: Word) -> eyre::Result<Word> {
+    async fn read_memory(&mut self, addr: Word) -> eyre::Result<Word> {
+        self.counters.memory_accesses += 1;
         match self.aligned(addr)? {
             Address::Local(a) => Ok(self.state.read_memory(a)?),
-            Address::Global(a) => {
-                let memory = &self.global_memory.read().await;
-                Ok(*memory.get(&a).unwrap_or(&0))
-            }
+            Address::Global(a) => Ok(self
+                .global_memory
+                .read()
+                .expect("memory lock poisoned")
+                .read(a / WORD_SIZE)),
         }
     }
 
     fn aligned(&self, addr: Word) -> eyre::Result<Address> {
-        eyre::ensure!(addr % WORD_SIZE == 0, "Misaligned address: 0x{addr:x}");
+        eyre::ensure!(
+            addr.is_multiple_of(WORD_SIZE),
+            "Misaligned address: 0x{addr:x}"
+        );
 
         if addr >> (WORD_SIZE * 8 - 1) == 0 {
             Ok(Address::Local(addr))
@@ -189,14 +362,35 @@ impl ThreadCtx {
     }
 
     async fn write_memory(&mut self, addr: Word, val: Word) -> eyre::Result<()> {
+        self.counters.memory_accesses += 1;
         match self.aligned(addr)? {
             Address::Local(a) => Ok(self.state.write_memory(a, val)?),
             Address::Global(a) => {
-                self.global_memory.write().await.insert(a, val);
+                self.global_memory
+                    .write()
+                    .expect("memory lock poisoned")
+                    .write(a / WORD_SIZE, val);
                 Ok(())
             }
         }
     }
+
+    fn read_frame(&mut self, offset: Word) -> eyre::Result<Word> {
+        self.counters.memory_accesses += 1;
+        let addr = self.state.frame_address(offset)?;
+        self.state.read_memory(addr)
+    }
+
+    fn write_frame(&mut self, offset: Word, val: Word) -> eyre::Result<()> {
+        self.counters.memory_accesses += 1;
+        let addr = self.state.frame_address(offset)?;
+        self.state.write_memory(addr, val)
+    }
+}
+
+fn shift_amount(b: Word) -> eyre::Result<u32> {
+    eyre::ensure!(b < WORD_SIZE * 8, "Shift amount out of range: {b}");
+    Ok(b as u32)
 }
 
 fn to_global(addr: u64) -> u64 {
@@ -213,14 +407,10 @@ impl Deref for ThreadCtx {
 
 pub async fn execute_at_path(path: &Path) -> eyre::Result<Word> {
     // TODO(shelbyd): Catch panics?
-    let contents = tokio::fs::read_to_string(path)
-        .await
-        .with_context(|| format!("Reading {}", path.display()))?;
-
-    let program = Program::parse(&contents)?;
+    let program = Program::load_file(path)?;
 
     let host = spawn_host(RealEal).await?;
-    Ok(host.execute(program).await?)
+    host.execute(program).await
 }
 
 pub async fn spawn_host<E: Eal>(eal: E) -> eyre::Result<Arc<HostCtx>> {
@@ -230,6 +420,9 @@ pub async fn spawn_host<E: Eal>(eal: E) -> eyre::Result<Arc<HostCtx>> {
 
     let host = Arc::new(HostCtx {
         eal: Box::new(eal),
+        rand: rand.get("processes"),
+        process_count: Default::default(),
+        channels: Channels::new(rand.get("channels"), Arc::clone(&peers)),
         spawner: spawner::Spawner::new(rand, Arc::clone(&peers)),
         eprint: Default::default(),
         peers,
@@ -242,51 +435,32 @@ pub async fn spawn_host<E: Eal>(eal: E) -> eyre::Result<Arc<HostCtx>> {
 #[derive(Debug, Clone)]
 pub struct Program {
     ops: Vec<OpCode>,
+    /// Where each op was written, `spans[i]` being the span of `ops[i]`.
+    spans: Vec<Span>,
+    sources: Vec<Source>,
+    /// Initial thread-local memory of the root thread, by address.
+    data: BTreeMap<Word, Word>,
+    /// Initial global memory, by address.
+    global_data: BTreeMap<Word, Word>,
 }
 
-impl Program {
-    pub fn parse(s: &str) -> eyre::Result<Program> {
-        let relevant_lines = s
-            .lines()
-            .map(|s| match s.split_once("#") {
-                Some((pre, _)) => pre,
-                None => s,
-            })
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .collect::<Vec<_>>();
-
-        let mut ops_seen = 0;
-        let mut labels = HashMap::new();
-        for line in &relevant_lines {
-            if let Some(label) = line.strip_prefix(":") {
-                let existing = labels.insert(label, ops_seen);
-                eyre::ensure!(existing.is_none(), "Duplicate label: {label}");
-            } else {
-                ops_seen += 1;
-            }
-        }
-
-        let mut ops = Vec::new();
-        for line in relevant_lines {
-            if line.starts_with(":") {
-                continue;
-            }
-
-            let (command, args) = match line.split_once(" ") {
-                None => (line, Vec::new()),
-                Some((command, args)) => (command, args.split(", ").collect()),
-            };
+/// Programs are equal if they run the same, wherever they were parsed from.
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.ops == other.ops && self.data == other.data && self.global_data == other.global_data
+    }
+}
 
-            let op = OpCode::parse(command, &args, &labels)?;
-            ops.push(op);
-        }
+impl Eq for Program {}
 
-        Ok(Program { ops })
+impl Program {
+    /// How many ops the program is made of.
+    pub fn op_count(&self) -> usize {
+        self.ops.len()
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum ValSp {
     Literal(Word),
 
@@ -298,96 +472,55 @@ enum ValSp {
     Memory(Box<ValSp>),
     GlobalMemory(Box<ValSp>),
 
+    Frame(Box<ValSp>),
+    FramePointer,
+
     ThreadId,
 }
 
-impl ValSp {
-    fn parse(s: &str, labels: &HashMap<&str, u64>) -> eyre::Result<ValSp> {
-        if let Some(label) = s.strip_prefix(":") {
-            return Ok(ValSp::Literal(
-                *labels
-                    .get(label)
-                    .ok_or_eyre(format!("Unknown label: {label}"))?,
-            ));
-        }
-
-        let box_parse = |s| {
-            eyre::Ok(Box::new(
-                ValSp::parse(s, labels).context(format!("Parsing {s}"))?,
-            ))
-        };
-
-        match indexed_expr("$pop", s)? {
-            None => {}
-            Some(None) => return Ok(ValSp::Pop),
-            Some(Some(i)) => return Ok(ValSp::PopI(box_parse(i)?)),
-        }
-
-        if s == "$peek" {
-            return Ok(ValSp::Peek);
-        }
-
-        match indexed_expr("$mem", s)? {
-            None => {}
-            Some(None) => eyre::bail!("$mem requires index"),
-            Some(Some(addr)) => return Ok(ValSp::Memory(box_parse(addr)?)),
-        }
-
-        match indexed_expr("$gmem", s)? {
-            None => {}
-            Some(None) => eyre::bail!("$gmem requires index"),
-            Some(Some(addr)) => return Ok(ValSp::GlobalMemory(box_parse(addr)?)),
-        }
-
-        if s == "$tid" {
-            return Ok(ValSp::ThreadId);
-        }
-
-        Ok(ValSp::Literal(parse_literal(s)?))
-    }
+/// The top of a stack, copied without allocating so it can be taken before every op.
+struct StackTop {
+    top: [Word; TRAP_STACK_WORDS],
+    len: usize,
 }
 
-fn parse_literal(s: &str) -> eyre::Result<Word> {
-    if let Some(hex) = s.strip_prefix("0x") {
-        let v = Word::from_str_radix(hex, 16).context(format!("Parsing as hex: {hex:?}"))?;
-        return Ok(v);
+impl StackTop {
+    fn of(stack: &[Word]) -> StackTop {
+        let words = &stack[stack.len().saturating_sub(TRAP_STACK_WORDS)..];
+        let mut top = [0; TRAP_STACK_WORDS];
+        top[..words.len()].copy_from_slice(words);
+        StackTop {
+            top,
+            len: stack.len(),
+        }
     }
 
-    if let Ok(v) = s.parse() {
-        return Ok(v);
-    }
-
-    eyre::bail!("Could not parse as literal value: {s:?}")
-}
-
-fn strip_square_braces(s: &str) -> eyre::Result<Option<&str>> {
-    let Some(without_first) = s.strip_prefix("[") else {
-        return Ok(None);
-    };
-
-    let result = without_first
-        .strip_suffix("]")
-        .ok_or_eyre(format!("Expected ']' at end of: {s}"))?;
-
-    Ok(Some(result))
-}
-
-fn indexed_expr<'s>(expr: &str, s: &'s str) -> eyre::Result<Option<Option<&'s str>>> {
-    let Some(with_expr) = s.strip_prefix(expr) else {
-        return Ok(None);
-    };
-
-    match strip_square_braces(with_expr)? {
-        None => return Ok(Some(None)),
-        Some(in_braces) => Ok(Some(Some(in_braces))),
+    fn words(&self) -> &[Word] {
+        &self.top[..self.len.min(TRAP_STACK_WORDS)]
     }
 }
 
 #[derive(Debug, Clone)]
 struct ThreadState {
     stack: Vec<Word>,
-    memory: BTreeMap<Word, Word>,
+    /// Local memory by word, shared with forked threads until either writes.
+    memory: PagedMemory,
     instruction_pointer: u64,
+    frame: Frame,
+    call_stack: Vec<Return>,
+}
+
+/// Region of local memory owned by the current function, addressed with `$frame[offset]`.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    base: Word,
+    size: Word,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Return {
+    address: Word,
+    frame: Frame,
 }
 
 impl ThreadState {
@@ -396,6 +529,11 @@ impl ThreadState {
             stack: Default::default(),
             memory: Default::default(),
             instruction_pointer: 0,
+            frame: Frame {
+                base: FRAME_BASE,
+                size: 0,
+            },
+            call_stack: Default::default(),
         }
     }
 
@@ -405,11 +543,14 @@ impl ThreadState {
 
     fn read_memory(&self, addr: Word) -> eyre::Result<Word> {
         let addr = self.aligned_local(addr)?;
-        Ok(*self.memory.get(&addr).unwrap_or(&0))
+        Ok(self.memory.read(addr))
     }
 
     fn aligned_local(&self, addr: Word) -> eyre::Result<Word> {
-        eyre::ensure!(addr % WORD_SIZE == 0, "Misaligned address: 0x{addr:x}");
+        eyre::ensure!(
+            addr.is_multiple_of(WORD_SIZE),
+            "Misaligned address: 0x{addr:x}"
+        );
         eyre::ensure!(
             addr.leading_ones() == 0,
             "Attempted to access global address in state: 0x{addr:x}"
@@ -420,7 +561,7 @@ impl ThreadState {
 
     fn write_memory(&mut self, addr: Word, value: Word) -> eyre::Result<()> {
         let addr = self.aligned_local(addr)?;
-        self.memory.insert(addr, value);
+        self.memory.write(addr, value);
         Ok(())
     }
 
@@ -434,12 +575,86 @@ impl ThreadState {
 
         Ok(())
     }
+
+    fn call(&mut self, addr: Word, program: &Program) -> eyre::Result<()> {
+        eyre::ensure!(
+            self.call_stack.len() < MAX_CALL_DEPTH,
+            "Call stack overflow: depth {MAX_CALL_DEPTH}"
+        );
+
+        let address = self.instruction_pointer;
+        self.jump_to(addr, program)?;
+
+        let caller = self.frame;
+        self.call_stack.push(Return {
+            address,
+            frame: caller,
+        });
+        self.frame = Frame {
+            base: caller.base + caller.size,
+            size: 0,
+        };
+
+        Ok(())
+    }
+
+    fn ret(&mut self) -> eyre::Result<()> {
+        let ret = self
+            .call_stack
+            .pop()
+            .ok_or_eyre("Return with empty call stack")?;
+        self.frame = ret.frame;
+        self.instruction_pointer = ret.address;
+
+        Ok(())
+    }
+
+    /// Sizes the current frame to `size` bytes, all zeroed.
+    fn enter(&mut self, size: Word) -> eyre::Result<()> {
+        eyre::ensure!(
+            size.is_multiple_of(WORD_SIZE),
+            "Misaligned frame size: {size}"
+        );
+        let end = self.frame.base.checked_add(size);
+        eyre::ensure!(
+            end.is_some_and(|end| end <= to_global(0)),
+            "Frame exceeds local memory: {size} bytes at 0x{:x}",
+            self.frame.base
+        );
+
+        let start = self.frame.base / WORD_SIZE;
+        self.memory.clear(start..start + size / WORD_SIZE);
+
+        self.frame.size = size;
+        Ok(())
+    }
+
+    fn frame_address(&self, offset: Word) -> eyre::Result<Word> {
+        eyre::ensure!(
+            offset < self.frame.size,
+            "Frame offset out of bounds: {offset} >= {}",
+            self.frame.size
+        );
+        Ok(self.frame.base + offset)
+    }
 }
 
 #[derive(Debug)]
 enum ThreadResult {
     Exit(Word),
     Finish(Word),
+    /// Stopped by KILL.
+    Killed,
+}
+
+impl ThreadResult {
+    /// What the process exits with if this thread ends it, unless it was killed.
+    fn code(&self) -> Option<Word> {
+        match *self {
+            ThreadResult::Exit(code) | ThreadResult::Finish(code) => Some(code),
+            ThreadResult::Killed => None,
+        }
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
@@ -450,8 +665,8 @@ enum Address {
 
 macro_rules! op_codes {
     ({$($name: ident => |$ctx:ident, $($arg:ident),*| $body:tt)*}) => {
-        #[allow(non_camel_case_types)]
-        #[derive(Debug, Clone)]
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        #[derive(Debug, Clone, PartialEq, Eq)]
         enum OpCode {
             $($name {
                 $($arg: ValSp),*
@@ -459,15 +674,17 @@ macro_rules! op_codes {
         }
 
         impl OpCode {
-            fn parse(command: &str, args: &[&str], labels: &HashMap<&str, Word>) -> eyre::Result<OpCode> {
+            const NAMES: &[&str] = &[$(stringify!($name)),*];
+            /// Names of each op's arguments, in the order of [`OpCode::NAMES`].
+            #[cfg(test)]
+            const ARG_NAMES: &[&[&str]] = &[$(&[$(stringify!($arg)),*]),*];
+
+            fn from_args(command: &str, args: Vec<ValSp>) -> eyre::Result<OpCode> {
                 match command {
                     $(stringify!($name) => {
-                        let mut args_iter = args.iter();
+                        let mut args_iter = args.into_iter();
                         let result = OpCode::$name {
-                            $($arg: {
-                                let arg = args_iter.next().ok_or_eyre(format!("Too few arguments to {command}"))?;
-                                ValSp::parse(arg, labels).context(format!("Parsing {arg}"))?
-                            }),*
+                            $($arg: args_iter.next().ok_or_eyre(format!("Too few arguments to {command}"))?),*
                         };
                         eyre::ensure!(args_iter.next().is_none(), "Too many arguments to {command}");
                         Ok(result)
@@ -477,6 +694,25 @@ macro_rules! op_codes {
                 }
             }
 
+            fn name(&self) -> &'static str {
+                match self {
+                    $(OpCode::$name { .. } => stringify!($name),)*
+                }
+            }
+
+            /// Names of the arguments, in the order of [`OpCode::args`].
+            fn arg_names(&self) -> &'static [&'static str] {
+                match self {
+                    $(OpCode::$name { .. } => &[$(stringify!($arg)),*],)*
+                }
+            }
+
+            fn args(&self) -> Vec<&ValSp> {
+                match self {
+                    $(OpCode::$name { $($arg),* } => vec![$($arg),*],)*
+                }
+            }
+
             async fn execute(&self, ctx: &mut ThreadCtx) -> eyre::Result<Option<ThreadResult>> {
                 match self {
                     $(OpCode::$name { $($arg),* } => {
@@ -511,36 +747,263 @@ op_codes!({
         ctx.state.push(v);
     }
 
+    // Plain arithmetic traps on overflow. `_WRAP` and `_SAT` variants wrap or saturate instead.
     ADD => |ctx, a, b| {
-        ctx.state.push(a + b);
+        ctx.state.push(a.checked_add(b).ok_or_eyre(format!("Overflow: {a} + {b}"))?);
     }
     SUB => |ctx, a, b| {
-        ctx.state.push(a - b);
+        ctx.state.push(a.checked_sub(b).ok_or_eyre(format!("Overflow: {a} - {b}"))?);
     }
     MUL => |ctx, a, b| {
-        ctx.state.push(a * b);
+        ctx.state.push(a.checked_mul(b).ok_or_eyre(format!("Overflow: {a} * {b}"))?);
     }
     DIV => |ctx, a, b| {
-        ctx.state.push(a / b);
+        ctx.state.push(a.checked_div(b).ok_or_eyre(format!("Division by zero: {a} / {b}"))?);
+    }
+    MOD => |ctx, a, b| {
+        ctx.state.push(a.checked_rem(b).ok_or_eyre(format!("Division by zero: {a} % {b}"))?);
+    }
+
+    ADD_WRAP => |ctx, a, b| {
+        ctx.state.push(a.wrapping_add(b));
+    }
+    SUB_WRAP => |ctx, a, b| {
+        ctx.state.push(a.wrapping_sub(b));
+    }
+    MUL_WRAP => |ctx, a, b| {
+        ctx.state.push(a.wrapping_mul(b));
+    }
+
+    ADD_SAT => |ctx, a, b| {
+        ctx.state.push(a.saturating_add(b));
+    }
+    SUB_SAT => |ctx, a, b| {
+        ctx.state.push(a.saturating_sub(b));
+    }
+    MUL_SAT => |ctx, a, b| {
+        ctx.state.push(a.saturating_mul(b));
+    }
+
+    // Signed arithmetic, treating words as two's complement.
+    IADD => |ctx, a, b| {
+        let r = (a as i64).checked_add(b as i64);
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: {} + {}", a as i64, b as i64))? as Word);
+    }
+    ISUB => |ctx, a, b| {
+        let r = (a as i64).checked_sub(b as i64);
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: {} - {}", a as i64, b as i64))? as Word);
+    }
+    IMUL => |ctx, a, b| {
+        let r = (a as i64).checked_mul(b as i64);
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: {} * {}", a as i64, b as i64))? as Word);
+    }
+    IDIV => |ctx, a, b| {
+        eyre::ensure!(b != 0, "Division by zero: {} / 0", a as i64);
+        let r = (a as i64).checked_div(b as i64);
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: {} / {}", a as i64, b as i64))? as Word);
+    }
+    IMOD => |ctx, a, b| {
+        eyre::ensure!(b != 0, "Division by zero: {} % 0", a as i64);
+        let r = (a as i64).checked_rem(b as i64);
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: {} % {}", a as i64, b as i64))? as Word);
+    }
+    INEG => |ctx, a| {
+        let r = (a as i64).checked_neg();
+        ctx.state.push(r.ok_or_eyre(format!("Overflow: -({})", a as i64))? as Word);
+    }
+
+    // Floating point arithmetic on the f64 bit pattern of a word.
+    FADD => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) + f64::from_bits(b)).to_bits());
+    }
+    FSUB => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) - f64::from_bits(b)).to_bits());
+    }
+    FMUL => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) * f64::from_bits(b)).to_bits());
+    }
+    FDIV => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) / f64::from_bits(b)).to_bits());
+    }
+    FNEG => |ctx, a| {
+        ctx.state.push((-f64::from_bits(a)).to_bits());
+    }
+    FSQRT => |ctx, a| {
+        ctx.state.push(f64::from_bits(a).sqrt().to_bits());
+    }
+
+    ITOF => |ctx, a| {
+        ctx.state.push((a as i64 as f64).to_bits());
+    }
+    UTOF => |ctx, a| {
+        ctx.state.push((a as f64).to_bits());
+    }
+    FTOI => |ctx, a| {
+        let f = f64::from_bits(a).trunc();
+        eyre::ensure!(
+            f >= i64::MIN as f64 && f < i64::MAX as f64,
+            "Float out of integer range: {f}"
+        );
+        ctx.state.push(f as i64 as Word);
+    }
+    FTOU => |ctx, a| {
+        let f = f64::from_bits(a).trunc();
+        eyre::ensure!(f >= 0.0 && f < Word::MAX as f64, "Float out of integer range: {f}");
+        ctx.state.push(f as Word);
+    }
+
+    AND => |ctx, a, b| {
+        ctx.state.push(a & b);
+    }
+    OR => |ctx, a, b| {
+        ctx.state.push(a | b);
+    }
+    XOR => |ctx, a, b| {
+        ctx.state.push(a ^ b);
+    }
+    NOT => |ctx, a| {
+        ctx.state.push(!a);
     }
 
     SHIFT_LEFT => |ctx, a, b| {
-        ctx.state.push(a << b);
+        ctx.state.push(a << shift_amount(b)?);
+    }
+    SHIFT_RIGHT => |ctx, a, b| {
+        ctx.state.push(a >> shift_amount(b)?);
+    }
+    SHIFT_RIGHT_ARITH => |ctx, a, b| {
+        ctx.state.push(((a as i64) >> shift_amount(b)?) as Word);
     }
 
-    JUMP => |ctx, addr| {
-        ctx.state.jump_to(addr, &ctx.proc.program)?;
+    // Comparisons push 1 if true, 0 otherwise. `I`-prefixed variants compare as signed, `F` as f64.
+    EQ => |ctx, a, b| {
+        ctx.state.push((a == b) as Word);
+    }
+    NE => |ctx, a, b| {
+        ctx.state.push((a != b) as Word);
     }
-    JUMP_EQ => |ctx, a, b, addr| {
+    LT => |ctx, a, b| {
+        ctx.state.push((a < b) as Word);
+    }
+    LE => |ctx, a, b| {
+        ctx.state.push((a <= b) as Word);
+    }
+    GT => |ctx, a, b| {
+        ctx.state.push((a > b) as Word);
+    }
+    GE => |ctx, a, b| {
+        ctx.state.push((a >= b) as Word);
+    }
+    ILT => |ctx, a, b| {
+        ctx.state.push(((a as i64) < (b as i64)) as Word);
+    }
+    ILE => |ctx, a, b| {
+        ctx.state.push(((a as i64) <= (b as i64)) as Word);
+    }
+    IGT => |ctx, a, b| {
+        ctx.state.push(((a as i64) > (b as i64)) as Word);
+    }
+    IGE => |ctx, a, b| {
+        ctx.state.push(((a as i64) >= (b as i64)) as Word);
+    }
+
+    FEQ => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) == f64::from_bits(b)) as Word);
+    }
+    FLT => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) < f64::from_bits(b)) as Word);
+    }
+    FLE => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) <= f64::from_bits(b)) as Word);
+    }
+    FGT => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) > f64::from_bits(b)) as Word);
+    }
+    FGE => |ctx, a, b| {
+        ctx.state.push((f64::from_bits(a) >= f64::from_bits(b)) as Word);
+    }
+
+    JUMP => |ctx, target| {
+        ctx.state.jump_to(target, &ctx.proc.program)?;
+    }
+    JUMP_EQ => |ctx, a, b, target| {
         if a == b {
-            ctx.state.jump_to(addr, &ctx.proc.program)?;
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_NE => |ctx, a, b, target| {
+        if a != b {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_LT => |ctx, a, b, target| {
+        if a < b {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_LE => |ctx, a, b, target| {
+        if a <= b {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_GT => |ctx, a, b, target| {
+        if a > b {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_GE => |ctx, a, b, target| {
+        if a >= b {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_ILT => |ctx, a, b, target| {
+        if (a as i64) < (b as i64) {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_ILE => |ctx, a, b, target| {
+        if (a as i64) <= (b as i64) {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_IGT => |ctx, a, b, target| {
+        if (a as i64) > (b as i64) {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
+        }
+    }
+    JUMP_IGE => |ctx, a, b, target| {
+        if (a as i64) >= (b as i64) {
+            ctx.state.jump_to(target, &ctx.proc.program)?;
         }
     }
 
-    FORK => |ctx, addr| {
+    CALL => |ctx, target| {
+        ctx.state.call(target, &ctx.proc.program)?;
+    }
+    RET => |ctx, | {
+        ctx.state.ret()?;
+    }
+    ENTER => |ctx, size| {
+        ctx.state.enter(size)?;
+    }
+    STORE_FRAME => |ctx, offset, v| {
+        ctx.write_frame(offset, v)?;
+    }
+
+    JUMP_TABLE => |ctx, table, len, index| {
+        eyre::ensure!(index < len, "Jump table index out of bounds: {index} >= {len}");
+        let addr = index
+            .checked_mul(WORD_SIZE)
+            .and_then(|offset| table.checked_add(offset))
+            .ok_or_eyre(format!("Jump table entry overflows address space: 0x{table:x}[{index}]"))?;
+        let target = ctx.read_memory(addr).await?;
+        ctx.state.jump_to(target, &ctx.proc.program)?;
+    }
+
+    FORK => |ctx, target| {
         let mut fork_state = ctx.state.clone();
         fork_state.push(ctx.id);
-        fork_state.jump_to(addr, &ctx.program)?;
+        fork_state.jump_to(target, &ctx.program)?;
 
         let child_id = ctx.proc.spawn(fork_state).await?;
 
@@ -550,15 +1013,57 @@ op_codes!({
     }
     JOIN => |ctx, tid| {
         match ctx.join(tid).await? {
-            // TODO(shelbyd): Exit from child thread without join.
+            // The child's EXIT has already ended the process.
             ThreadResult::Exit(e) => return Ok(Some(ThreadResult::Exit(e))),
             ThreadResult::Finish(v) => ctx.state.push(v),
+            ThreadResult::Killed => eyre::bail!("Joined killed thread: {tid}"),
         }
     }
+    // Like JOIN, but pushes the result then 1, or 0 then 0 if the thread was killed.
+    TRY_JOIN => |ctx, tid| {
+        let (v, finished) = match ctx.join(tid).await? {
+            ThreadResult::Exit(e) => return Ok(Some(ThreadResult::Exit(e))),
+            ThreadResult::Finish(v) => (v, 1),
+            ThreadResult::Killed => (0, 0),
+        };
+        ctx.state.push(v);
+        ctx.state.push(finished);
+    }
+    // Stops a thread before its next op. Joining it then sees that it was killed.
+    KILL => |ctx, tid| {
+        ctx.spawner.kill(tid).await?;
+    }
+    // A detached thread can't be joined. Its EXIT still ends the process, and so does a trap,
+    // since nothing else could see it.
+    DETACH => |ctx, tid| {
+        ctx.spawner.detach(tid, &ctx.proc).await?;
+    }
     THREAD_FINISH => |_ctx, v| {
         return Ok(Some(ThreadResult::Finish(v)));
     }
 
+    // Channels are bounded queues of words, identified by the word CHAN_NEW pushes, that any
+    // thread of the process can send to and receive from. They're dropped when it ends.
+    CHAN_NEW => |ctx, capacity| {
+        let channel = ctx.channels.create(capacity, &ctx.proc).await?;
+        ctx.state.push(channel);
+    }
+    CHAN_SEND => |ctx, channel, v| {
+        ctx.channels.send(channel, v, &ctx.proc).await?;
+    }
+    // Pushes the value then 1, or 0 then 0 once the channel is closed and empty.
+    CHAN_RECV => |ctx, channel| {
+        let (v, ok) = match ctx.channels.recv(channel, &ctx.proc).await? {
+            Some(v) => (v, 1),
+            None => (0, 0),
+        };
+        ctx.state.push(v);
+        ctx.state.push(ok);
+    }
+    CHAN_CLOSE => |ctx, channel| {
+        ctx.channels.close(channel, &ctx.proc).await?;
+    }
+
     EXIT => |_ctx, v| {
         return Ok(Some(ThreadResult::Exit(v)));
     }
@@ -573,6 +1078,6 @@ op_codes!({
         for (i, w) in ctx.state.stack.iter().rev().enumerate() {
             eprintln!("{i}: 0x{w:x} ({w})");
         }
-        eprintln!("");
+        eprintln!();
     }
 });
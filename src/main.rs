@@ -15,8 +15,29 @@ struct Options {
 
 #[derive(StructOpt, Debug)]
 enum Command {
-    /// Run the provided file.
+    /// Run the provided flasm, .fl or bytecode file.
     Run { file: PathBuf },
+
+    /// Assemble a flasm or .fl file to bytecode.
+    Assemble {
+        file: PathBuf,
+
+        /// Where to write the bytecode, defaulting to `file` with a `.flbc` extension.
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+
+    /// Print a flasm, .fl or bytecode file as flasm.
+    Disasm { file: PathBuf },
+
+    /// Format flasm files in place.
+    Fmt {
+        files: Vec<PathBuf>,
+
+        /// List files that aren't formatted instead of formatting them.
+        #[structopt(long = "check")]
+        check: bool,
+    },
 }
 
 #[tokio::main]
@@ -29,14 +50,50 @@ async fn main() -> eyre::Result<ExitCode> {
             0 => log::Level::Warn,
             1 => log::Level::Info,
             2 => log::Level::Debug,
-            3 | _ => log::Level::Trace,
+            _ => log::Level::Trace,
         })
         .init()?;
 
     match &opts.command {
         Command::Run { file } => {
-            let status = flock::execute_at_path(&file).await?;
+            let status = flock::execute_at_path(file).await?;
             Ok(ExitCode::from(status as u8))
         }
+        Command::Assemble { file, output } => {
+            let program = flock::Program::load_file(file)?;
+            let output = output
+                .clone()
+                .unwrap_or_else(|| file.with_extension("flbc"));
+            std::fs::write(&output, program.to_bytes())?;
+            log::info!("Wrote {} ({:016x})", output.display(), program.hash());
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Disasm { file } => {
+            let program = flock::Program::load_file(file)?;
+            print!("{}", program.disassemble()?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Fmt { files, check } => {
+            let mut unformatted = 0;
+            for file in files {
+                let contents = std::fs::read_to_string(file)?;
+                let formatted = flock::format_flasm(&contents);
+                if formatted == contents {
+                    continue;
+                }
+
+                if *check {
+                    println!("{}", file.display());
+                    unformatted += 1;
+                } else {
+                    std::fs::write(file, formatted)?;
+                }
+            }
+            Ok(if unformatted > 0 {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            })
+        }
     }
 }
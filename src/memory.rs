@@ -0,0 +1,123 @@
+//! Word-addressed memory, split into pages that copies share until one of them writes.
+
+use std::{collections::BTreeMap, fmt, ops::Range, sync::Arc};
+
+use crate::Word;
+
+/// Words per page, 4 KiB.
+const PAGE_WORDS: Word = 512;
+/// Page slots per table, so a table maps 2 MiB.
+const TABLE_PAGES: Word = 512;
+
+type Page = [Word; PAGE_WORDS as usize];
+type Table = [Option<Arc<Page>>; TABLE_PAGES as usize];
+
+/// Memory indexed by word, where unwritten words are 0.
+///
+/// Words are found through a two level page table: a sparse directory of tables, each holding
+/// the pages of a 2 MiB region, so the whole 64 bit space can be addressed while a sequential
+/// access stays within one table. Only pages that were written hold memory.
+///
+/// Cloning is O(1). The first write to a clone copies the directory, which takes time in
+/// proportion to the 2 MiB regions in use, then the written page's table and the page itself.
+/// Each later write to another table or page copies just that one. Pages that are never written
+/// stay shared, so whole pages can be handed to other copies without copying their words.
+#[derive(Clone, Default)]
+pub(crate) struct PagedMemory {
+    /// Tables by table number.
+    tables: Arc<BTreeMap<Word, Arc<Table>>>,
+}
+
+impl PagedMemory {
+    pub(crate) fn from_words(words: impl IntoIterator<Item = (Word, Word)>) -> PagedMemory {
+        let mut memory = PagedMemory::default();
+        for (word, value) in words {
+            memory.write(word, value);
+        }
+        memory
+    }
+
+    pub(crate) fn read(&self, word: Word) -> Word {
+        self.page(word)
+            .map_or(0, |page| page[(word % PAGE_WORDS) as usize])
+    }
+
+    pub(crate) fn write(&mut self, word: Word, value: Word) {
+        if value == 0 && self.page(word).is_none() {
+            return;
+        }
+
+        let number = word / PAGE_WORDS;
+        let table = Arc::make_mut(&mut self.tables)
+            .entry(number / TABLE_PAGES)
+            .or_insert_with(|| Arc::new(std::array::from_fn(|_| None)));
+        let page = Arc::make_mut(table)[(number % TABLE_PAGES) as usize]
+            .get_or_insert_with(|| Arc::new([0; PAGE_WORDS as usize]));
+        Arc::make_mut(page)[(word % PAGE_WORDS) as usize] = value;
+    }
+
+    /// Sets the words in `range` to 0, freeing the pages it covers.
+    pub(crate) fn clear(&mut self, range: Range<Word>) {
+        if range.is_empty() {
+            return;
+        }
+        let first = range.start / PAGE_WORDS;
+        let last = (range.end - 1) / PAGE_WORDS;
+        let touched = self
+            .tables
+            .range(first / TABLE_PAGES..=last / TABLE_PAGES)
+            .map(|(&number, _)| number)
+            .collect::<Vec<_>>();
+        if touched.is_empty() {
+            return;
+        }
+
+        let tables = Arc::make_mut(&mut self.tables);
+        for table_number in touched {
+            let table_start = table_number * TABLE_PAGES;
+            let slots = first.max(table_start) - table_start
+                ..=last.min(table_start + TABLE_PAGES - 1) - table_start;
+            let Some(table) = tables.get_mut(&table_number) else {
+                continue;
+            };
+
+            for slot in slots {
+                if table[slot as usize].is_none() {
+                    continue;
+                }
+                let table = Arc::make_mut(table);
+                let page_start = (table_start + slot) * PAGE_WORDS;
+                let start = range.start.max(page_start) - page_start;
+                let end = range.end.min(page_start + PAGE_WORDS) - page_start;
+                if start == 0 && end == PAGE_WORDS {
+                    table[slot as usize] = None;
+                } else if let Some(page) = &mut table[slot as usize] {
+                    Arc::make_mut(page)[start as usize..end as usize].fill(0);
+                }
+            }
+
+            if table.iter().all(Option::is_none) {
+                tables.remove(&table_number);
+            }
+        }
+    }
+
+    fn page(&self, word: Word) -> Option<&Page> {
+        let number = word / PAGE_WORDS;
+        let table = self.tables.get(&(number / TABLE_PAGES))?;
+        table[(number % TABLE_PAGES) as usize].as_deref()
+    }
+}
+
+impl fmt::Debug for PagedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pages = self
+            .tables
+            .values()
+            .map(|table| table.iter().flatten().count())
+            .sum::<usize>();
+        f.debug_struct("PagedMemory")
+            .field("pages", &pages)
+            .finish()
+    }
+}
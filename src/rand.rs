@@ -24,7 +24,7 @@ impl Rand {
     }
 
     pub fn select<'t, T>(&self, nodes: &'t [T]) -> Option<&'t T> {
-        if nodes.len() == 0 {
+        if nodes.is_empty() {
             return None;
         }
 
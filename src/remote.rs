@@ -1,8 +1,10 @@
 use std::ops::Index;
 
+use tokio::sync::oneshot;
+
 use crate::{
     event::{Event, EventListener},
-    ThreadCtx,
+    ThreadCtx, Word,
 };
 
 pub(crate) struct Peers {
@@ -44,10 +46,43 @@ pub(crate) struct Peer {}
 
 impl Peer {
     pub(crate) fn send_message(&self, _message: Message) -> eyre::Result<()> {
-        todo!();
+        eyre::bail!("Sending messages to peers is not supported yet")
     }
 }
 
 pub(crate) enum Message {
+    #[allow(unused)]
     Spawn { context: ThreadCtx },
+    #[allow(unused)]
+    Kill { thread: Word },
+
+    // Operations on a channel held by the receiving peer, for a thread of `process`.
+    #[allow(unused)]
+    ChannelCreate {
+        process: Word,
+        channel: Word,
+        capacity: Word,
+    },
+    /// Replies once the value is queued, which waits while the channel is full, or with false
+    /// if the channel is closed.
+    #[allow(unused)]
+    ChannelSend {
+        process: Word,
+        channel: Word,
+        value: Word,
+        reply: oneshot::Sender<bool>,
+    },
+    #[allow(unused)]
+    ChannelRecv {
+        process: Word,
+        channel: Word,
+        reply: oneshot::Sender<Option<Word>>,
+    },
+    /// Replies with whether the channel was open.
+    #[allow(unused)]
+    ChannelClose {
+        process: Word,
+        channel: Word,
+        reply: oneshot::Sender<bool>,
+    },
 }
@@ -2,26 +2,37 @@ use std::{
     collections::HashMap,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Weak,
     },
 };
 
 use eyre::OptionExt;
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
 
 use crate::{
     rand::Rand,
-    remote::{Message, Peers},
+    remote::{Message, Peer, Peers},
     ProcessCtx, ThreadCtx, ThreadResult, ThreadState, Word,
 };
 
 pub(crate) struct Spawner {
     rand: Rand,
     spawn_count: AtomicU64,
-    threads: Mutex<HashMap<Word, JoinHandle<eyre::Result<ThreadResult>>>>,
+    /// Threads on this host, until they're joined or, if detached, until they end.
+    threads: Mutex<HashMap<Word, Thread>>,
     peers: Arc<Peers>,
 }
 
+struct Thread {
+    process: Weak<ProcessCtx>,
+    /// Taken when the thread is detached or joined.
+    handle: Option<JoinHandle<eyre::Result<ThreadResult>>>,
+    kill: watch::Sender<bool>,
+}
+
 impl Spawner {
     pub(crate) fn new(rand: Rand, peers: Arc<Peers>) -> Spawner {
         Spawner {
@@ -43,37 +54,109 @@ impl Spawner {
             .get(self.spawn_count.fetch_add(1, Ordering::Relaxed).to_string())
             .word();
 
+        let (kill, killed) = watch::channel(false);
         let context = ThreadCtx {
             id: thread_id,
             proc: Arc::clone(process),
             state,
+            killed,
+            counters: Default::default(),
         };
 
-        let locations = self.peers.len() + 1;
-        match (thread_id as usize) % locations {
-            0 => {
-                self.threads
-                    .lock()
-                    .await
-                    .insert(thread_id, spawn_execute(context));
+        match self.location(thread_id) {
+            None => {
+                // Locked before the thread starts, so that it's tracked before it can KILL or
+                // DETACH itself.
+                let mut threads = self.threads.lock().await;
+                let thread = Thread {
+                    process: Arc::downgrade(process),
+                    handle: Some(spawn_execute(context)),
+                    kill,
+                };
+                threads.insert(thread_id, thread);
             }
-            peer => self.peers[peer - 1].send_message(Message::Spawn { context })?,
+            Some(peer) => peer.send_message(Message::Spawn { context })?,
         }
 
         Ok(thread_id)
     }
 
     pub(crate) async fn join(&self, tid: Word) -> eyre::Result<ThreadResult> {
-        let handle = {
-            let mut threads = self.threads.lock().await;
-            threads
-                .remove(&tid)
-                .ok_or_eyre(format!("Joined unknown thread: {tid}"))?
-        };
-        Ok(handle.await??)
+        // Detached threads, and threads already being joined, are tracked until they end but
+        // have no handle to join.
+        let handle = self
+            .threads
+            .lock()
+            .await
+            .get_mut(&tid)
+            .and_then(|t| t.handle.take())
+            .ok_or_eyre(format!("Joined unknown thread: {tid}"))?;
+        let result = handle.await;
+        self.threads.lock().await.remove(&tid);
+        result?
+    }
+
+    /// Makes `tid` unjoinable, cleaning it up when it ends.
+    pub(crate) async fn detach(&self, tid: Word, process: &Arc<ProcessCtx>) -> eyre::Result<()> {
+        let handle = self
+            .threads
+            .lock()
+            .await
+            .get_mut(&tid)
+            .and_then(|t| t.handle.take())
+            .ok_or_eyre(format!("Detached unknown thread: {tid}"))?;
+
+        let process = Arc::clone(process);
+        tokio::task::spawn(async move {
+            let result = handle.await;
+            process.spawner.threads.lock().await.remove(&tid);
+            if let Ok(Err(e)) = result {
+                process.end(Err(e));
+            }
+        });
+        Ok(())
+    }
+
+    /// Asks `tid` to stop, which it does before its next op, or right away if it's waiting.
+    pub(crate) async fn kill(&self, tid: Word) -> eyre::Result<()> {
+        if let Some(peer) = self.location(tid) {
+            return peer.send_message(Message::Kill { thread: tid });
+        }
+
+        let threads = self.threads.lock().await;
+        let thread = threads
+            .get(&tid)
+            .ok_or_eyre(format!("Killed unknown thread: {tid}"))?;
+        thread.kill.send_replace(true);
+        Ok(())
+    }
+
+    /// Drops the threads of an ended process that were never joined.
+    pub(crate) async fn forget(&self, process: &Arc<ProcessCtx>) {
+        let process = Arc::downgrade(process);
+        self.threads
+            .lock()
+            .await
+            .retain(|_, thread| !thread.process.ptr_eq(&process));
+    }
+
+    /// The peer that runs `tid`, or `None` if this host does.
+    fn location(&self, tid: Word) -> Option<&Peer> {
+        let locations = self.peers.len() + 1;
+        match (tid as usize) % locations {
+            0 => None,
+            peer => Some(&self.peers[peer - 1]),
+        }
     }
 }
 
 fn spawn_execute(ctx: ThreadCtx) -> JoinHandle<Result<ThreadResult, eyre::Error>> {
-    tokio::task::spawn(async move { ctx.execute().await })
+    tokio::task::spawn(async move {
+        let process = Arc::clone(&ctx.proc);
+        let result = ctx.execute().await;
+        if let Ok(ThreadResult::Exit(code)) = result {
+            process.end(Ok(code));
+        }
+        result
+    })
 }
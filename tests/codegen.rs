@@ -0,0 +1,149 @@
+use std::{path::Path, time::Instant};
+
+use colored::Colorize;
+use flock::{Counters, Program};
+
+mod common;
+
+/// Compiles every `.fl` test and benchmark program with and without optimizing, checking that
+/// both run the same and that optimizing never makes the code bigger, or makes it execute more
+/// ops or memory accesses. Prints the executed op and memory access counts of each, which is how
+/// changes to the code generator are measured. Programs expected to fail must fail the same way
+/// at both levels.
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    eprintln!();
+
+    let start = Instant::now();
+    let mut failed = Vec::new();
+    let benches = std::fs::read_dir("benches")?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let paths = common::files()?
+        .into_iter()
+        .chain(benches)
+        .filter(|path| path.extension().is_some_and(|e| e == "fl"))
+        .collect::<Vec<_>>();
+    let mut totals = [Counters::default(); 2];
+
+    for path in &paths {
+        eprint!("test {} ... ", path.display());
+
+        if common::expected_error(path)?.is_some() {
+            match same_error(path).await {
+                Ok(()) => eprintln!("{} expected error at both levels", "ok".green()),
+                Err(e) => {
+                    eprintln!("{}", "FAILED".red());
+                    failed.push((path, e));
+                }
+            }
+            continue;
+        }
+
+        match compare(path).await {
+            Ok([naive, optimized]) => {
+                eprintln!(
+                    "{} {} -> {} ops, {} -> {} memory accesses executed",
+                    "ok".green(),
+                    naive.ops,
+                    optimized.ops,
+                    naive.memory_accesses,
+                    optimized.memory_accesses,
+                );
+                for (total, counts) in totals.iter_mut().zip([naive, optimized]) {
+                    total.ops += counts.ops;
+                    total.memory_accesses += counts.memory_accesses;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", "FAILED".red());
+                failed.push((path, e));
+            }
+        }
+    }
+
+    eprintln!();
+    for (path, e) in &failed {
+        eprintln!("test {} {}", path.display(), "FAILED".red());
+        eprintln!();
+        eprintln!("{e:?}");
+        eprintln!();
+    }
+
+    let [naive, optimized] = totals;
+    eprintln!(
+        "total: {} -> {} ops, {} -> {} memory accesses executed",
+        naive.ops, optimized.ops, naive.memory_accesses, optimized.memory_accesses
+    );
+    let result = if failed.is_empty() {
+        "ok".green()
+    } else {
+        "FAILED".red()
+    };
+    eprintln!(
+        "test result: {result}. {} passed; {} failed; finished in {:?}",
+        paths.len() - failed.len(),
+        failed.len(),
+        start.elapsed()
+    );
+    eprintln!();
+
+    eyre::ensure!(failed.is_empty(), "Code generation comparison failed");
+    Ok(())
+}
+
+async fn compare(path: &Path) -> eyre::Result<[Counters; 2]> {
+    let source = std::fs::read_to_string(path)?;
+    let naive = Program::compile_unoptimized(&source)?;
+    let optimized = Program::compile(&source)?;
+    eyre::ensure!(
+        optimized.op_count() <= naive.op_count(),
+        "Optimizing added ops: {} -> {}",
+        naive.op_count(),
+        optimized.op_count()
+    );
+
+    // The same seed places threads the same way, so the counts only differ by the code.
+    let seed = rand::random();
+    let mut counts = [Counters::default(); 2];
+    for (program, counts) in [naive, optimized].into_iter().zip(&mut counts) {
+        let (code, counters) = common::execute_counted_with_seed(program, seed).await?;
+        eyre::ensure!(code == 0, "Program exited with code: {code}");
+        *counts = counters;
+    }
+    let [naive, optimized] = counts;
+    eyre::ensure!(
+        optimized.ops <= naive.ops,
+        "Optimizing executed more ops: {} -> {}",
+        naive.ops,
+        optimized.ops
+    );
+    eyre::ensure!(
+        optimized.memory_accesses <= naive.memory_accesses,
+        "Optimizing executed more memory accesses: {} -> {}",
+        naive.memory_accesses,
+        optimized.memory_accesses
+    );
+    Ok(counts)
+}
+
+async fn same_error(path: &Path) -> eyre::Result<()> {
+    let source = std::fs::read_to_string(path)?;
+    let compilers: [fn(&str) -> eyre::Result<Program>; 2] =
+        [Program::compile_unoptimized, Program::compile];
+    for compile in compilers {
+        let result = match compile(&source) {
+            Ok(program) => common::execute_program_with_seed(program, rand::random()).await,
+            Err(e) => Err(e),
+        };
+        // Compiling from a string names the source `<input>` rather than its path.
+        let result = result.map_err(|e| {
+            eyre::eyre!(
+                "{}",
+                format!("{e:?}").replace("<input>", &path.display().to_string())
+            )
+        });
+        common::check_result(path, result)?;
+    }
+    Ok(())
+}
@@ -3,11 +3,11 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use eyre::Context;
-use flock::{rand::Rand, spawn_host, Eal, Program, Word};
+use flock::{rand::Rand, spawn_host, Counters, Eal, Program, Word};
 
 pub fn files() -> eyre::Result<BTreeSet<PathBuf>> {
     Ok(walkdir::WalkDir::new("tests")
@@ -15,22 +15,57 @@ pub fn files() -> eyre::Result<BTreeSet<PathBuf>> {
         .collect::<Result<Vec<_>, _>>()?
         .into_iter()
         .filter(|f| f.file_type().is_file())
-        .filter(|f| f.path().extension() == Some(OsStr::new("flasm")))
+        .filter(|f| {
+            let extension = f.path().extension();
+            extension == Some(OsStr::new("flasm")) || extension == Some(OsStr::new("fl"))
+        })
+        // Libraries are only run through the tests that include them.
+        .filter(|f| !f.path().components().any(|c| c.as_os_str() == "lib"))
         .map(|f| f.path().to_owned())
         .collect::<BTreeSet<_>>())
 }
 
+/// Programs that are expected to run successfully.
 pub fn programs() -> eyre::Result<BTreeMap<PathBuf, Program>> {
     files()?
         .into_iter()
+        .filter(|path| matches!(expected_error(path), Ok(None)))
         .map(|path| {
-            let contents =
-                std::fs::read_to_string(&path).context(format!("Reading {}", path.display()))?;
-            Ok((path, Program::parse(&contents)?))
+            let program =
+                Program::load_file(&path).context(format!("Parsing {}", path.display()))?;
+            Ok((path, program))
         })
         .collect()
 }
 
+/// Programs that are expected to fail declare it with a `# expect-error: <message>` comment.
+pub fn expected_error(path: &Path) -> eyre::Result<Option<String>> {
+    let contents = std::fs::read_to_string(path).context(format!("Reading {}", path.display()))?;
+    Ok(contents.lines().find_map(|l| {
+        l.trim()
+            .strip_prefix("# expect-error:")
+            .map(|e| e.trim().to_string())
+    }))
+}
+
+pub fn check_result(path: &Path, result: eyre::Result<Word>) -> eyre::Result<()> {
+    match (result, expected_error(path)?) {
+        (Ok(0), None) => Ok(()),
+        (Ok(c), None) => eyre::bail!("Program exited with code: {c}"),
+        (Ok(c), Some(expected)) => {
+            eyre::bail!("Expected error containing {expected:?}, exited with code: {c}")
+        }
+        (Err(e), None) => Err(e),
+        (Err(e), Some(expected)) => {
+            if format!("{e:?}").contains(&expected) {
+                Ok(())
+            } else {
+                Err(e.wrap_err(format!("Expected error containing {expected:?}")))
+            }
+        }
+    }
+}
+
 pub struct RandomVm {
     rand: Rand,
 }
@@ -43,6 +78,13 @@ impl Eal for RandomVm {
 }
 
 pub async fn execute_program_with_seed(program: Program, seed: u64) -> eyre::Result<Word> {
+    Ok(execute_counted_with_seed(program, seed).await?.0)
+}
+
+pub async fn execute_counted_with_seed(
+    program: Program,
+    seed: u64,
+) -> eyre::Result<(Word, Counters)> {
     let rand = Rand::new(seed);
     let node_count = (rand.get("host_processes").poisson(3.0) as usize).max(1);
 
@@ -54,5 +96,5 @@ pub async fn execute_program_with_seed(program: Program, seed: u64) -> eyre::Res
     .await?;
 
     let node = rand.get("root_node").select(&nodes).unwrap();
-    Ok(node.execute(program).await?)
+    node.execute_counted(program).await
 }
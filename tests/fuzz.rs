@@ -12,7 +12,7 @@ mod common;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    eprintln!("");
+    eprintln!();
 
     let fuzz = std::env::var("FUZZ");
     let mut fuzz_for = match fuzz.as_ref().map(|s| s.as_str()) {
@@ -42,12 +42,10 @@ async fn main() -> eyre::Result<()> {
         let seed: u64 = rand::random();
 
         let (path, program) = programs.next().unwrap();
-        let failed = match execute_program_with_seed(program.clone(), seed).await {
-            Ok(0) => {
-                passed += 1;
-                continue;
-            }
-            r => r,
+        let result = execute_program_with_seed(program.clone(), seed).await;
+        let Err(err) = common::check_result(path, result) else {
+            passed += 1;
+            continue;
         };
 
         let mut file = std::fs::OpenOptions::new()
@@ -56,10 +54,6 @@ async fn main() -> eyre::Result<()> {
             .open("tests/found_with_fuzzing.txt")?;
         writeln!(file, "{} {seed}", path.display())?;
 
-        let err = match failed {
-            Err(e) => e,
-            Ok(c) => eyre::eyre!(format!("Program exited with code: {c}")),
-        };
         return Err(err).context(format!("{} {seed}", path.display()));
     }
 
@@ -68,7 +62,7 @@ async fn main() -> eyre::Result<()> {
         "ok".green(),
         start.elapsed()
     );
-    eprintln!("");
+    eprintln!();
 
     Ok(())
 }
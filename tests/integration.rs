@@ -6,7 +6,7 @@ mod common;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    eprintln!("");
+    eprintln!();
 
     let start = Instant::now();
 
@@ -20,24 +20,15 @@ async fn main() -> eyre::Result<()> {
 
         let result = flock::execute_at_path(&file).await;
 
-        let result = match result {
-            Ok(0) => {
-                eprintln!("{ok}");
-                Ok(())
-            }
-            Ok(e) => {
-                eprintln!("{failed}");
-                Err(eyre::eyre!("Program exited with code: {e}"))
-            }
-            Err(e) => {
-                eprintln!("{failed}");
-                Err(e)
-            }
-        };
+        let result = common::check_result(&file, result);
+        match &result {
+            Ok(()) => eprintln!("{ok}"),
+            Err(_) => eprintln!("{failed}"),
+        }
         results.insert(file, result);
     }
 
-    eprintln!("");
+    eprintln!();
 
     for (file, result) in &results {
         let Err(e) = result else {
@@ -45,9 +36,9 @@ async fn main() -> eyre::Result<()> {
         };
 
         eprintln!("test {} {failed}", file.display());
-        eprintln!("");
+        eprintln!();
         eprintln!("{e:?}");
-        eprintln!("");
+        eprintln!();
     }
 
     let success = results.values().filter(|r| r.is_ok()).count();
@@ -59,7 +50,7 @@ async fn main() -> eyre::Result<()> {
         start.elapsed()
     );
 
-    eprintln!("");
+    eprintln!();
 
     Ok(())
 }
@@ -0,0 +1,139 @@
+use std::{path::Path, time::Instant};
+
+use colored::Colorize;
+use flock::Program;
+
+mod common;
+
+/// Checks that every test program survives being formatted, encoded as bytecode and
+/// disassembled.
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    eprintln!();
+
+    let start = Instant::now();
+    let mut failed = Vec::new();
+    let programs = common::programs()?;
+
+    for (path, program) in &programs {
+        eprint!("test {} ... ", path.display());
+
+        let result = match fmt_roundtrip(path).and_then(|()| disasm_roundtrip(program)) {
+            Ok(()) => bytecode_roundtrip(program).await,
+            Err(e) => Err(e),
+        };
+        match &result {
+            Ok(()) => eprintln!("{}", "ok".green()),
+            Err(_) => eprintln!("{}", "FAILED".red()),
+        }
+        if let Err(e) = result {
+            failed.push((path.clone(), e));
+        }
+    }
+
+    let snippets = Path::new("formatting snippets");
+    eprint!("test {} ... ", snippets.display());
+    match fmt_snippets() {
+        Ok(()) => eprintln!("{}", "ok".green()),
+        Err(e) => {
+            eprintln!("{}", "FAILED".red());
+            failed.push((snippets.into(), e));
+        }
+    }
+
+    eprintln!();
+    for (path, e) in &failed {
+        eprintln!("test {} {}", path.display(), "FAILED".red());
+        eprintln!();
+        eprintln!("{e:?}");
+        eprintln!();
+    }
+
+    let result = if failed.is_empty() {
+        "ok".green()
+    } else {
+        "FAILED".red()
+    };
+    eprintln!(
+        "test result: {result}. {} passed; {} failed; finished in {:?}",
+        programs.len() + 1 - failed.len(),
+        failed.len(),
+        start.elapsed()
+    );
+    eprintln!();
+
+    eyre::ensure!(failed.is_empty(), "Round trip failed");
+    Ok(())
+}
+
+/// Formatting is stable and doesn't change what a program assembles to.
+fn fmt_roundtrip(path: &Path) -> eyre::Result<()> {
+    if path.extension().is_some_and(|e| e == "fl") {
+        return Ok(());
+    }
+
+    let contents = std::fs::read_to_string(path)?;
+    let formatted = flock::format_flasm(&contents);
+    eyre::ensure!(
+        flock::format_flasm(&formatted) == formatted,
+        "Formatting again changed the file"
+    );
+
+    // Includes are resolved from the current directory, so only standalone programs parse here.
+    if let Ok(program) = Program::parse(&contents) {
+        eyre::ensure!(
+            Program::parse(&formatted)? == program,
+            "Formatting changed the program"
+        );
+    }
+    Ok(())
+}
+
+/// Source the formatter could read differently from the assembler.
+const FMT_SNIPPETS: &[&str] = &[
+    // A single label named "start PUSH 1", not a label and an op.
+    ":start PUSH 1\nPUSH 2\n",
+    "  PUSH\t1\nADD   $pop ,2   # A comment, with a comma\n",
+    "STORE 0x10,\t$pop[ 1 ]\nASSERT_EQ $mem[0x10],(1 + 2) * 3\n",
+    ".equ X,  1 + 2\n.define Y X * 2\nADD X,Y\n",
+    ".data\n:s\n.string \"a, # b\\\"  c\"\n.word 1,2 ,  3\n",
+    ".macro twice v\nADD %v ,%v\n.endmacro\ntwice 4\n",
+];
+
+fn fmt_snippets() -> eyre::Result<()> {
+    for snippet in FMT_SNIPPETS {
+        let formatted = flock::format_flasm(snippet);
+        eyre::ensure!(
+            Program::parse(&formatted)? == Program::parse(snippet)?,
+            "Formatting changed the program:\n{snippet}\nformatted as:\n{formatted}"
+        );
+    }
+    Ok(())
+}
+
+fn disasm_roundtrip(program: &Program) -> eyre::Result<()> {
+    let flasm = program.disassemble()?;
+    let parsed = Program::parse(&flasm)?;
+    eyre::ensure!(
+        &parsed == program,
+        "Disassembly parsed into a different program:\n{flasm}"
+    );
+    Ok(())
+}
+
+async fn bytecode_roundtrip(program: &Program) -> eyre::Result<()> {
+    let bytes = program.to_bytes();
+    let decoded = Program::from_bytes(&bytes)?;
+    eyre::ensure!(
+        decoded.to_bytes() == bytes,
+        "Bytecode changed after decoding"
+    );
+    eyre::ensure!(
+        decoded.hash() == program.hash(),
+        "Hash changed after decoding"
+    );
+
+    let code = common::execute_program_with_seed(decoded, rand::random()).await?;
+    eyre::ensure!(code == 0, "Decoded program exited with code: {code}");
+    Ok(())
+}